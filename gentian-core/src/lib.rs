@@ -0,0 +1,75 @@
+//! Runtime support for [gentian](https://docs.rs/gentian).
+//!
+//! # `Awaitable`
+//!  With `#[gentian_attr(awaitable)]`, `co_await` works on any type implementing [`Awaitable`] instead of only `Poll`.
+//!  While `into_ready` returns `None`, the function returns its `pending` value (`Poll::Pending` by default).
+//!  ````ignore
+//!  #[gentian]
+//!  #[gentian_attr(state=self.state, ret_val=Step::Done, awaitable, pending=Step::Again)]
+//!  fn step(&mut self) -> Step {
+//!      let v = co_await(self.dev.try_read());
+//!      ...
+//!  }
+//!  ````
+//!
+//! # `StateInfo`
+//!  With `#[gentian_attr(state_info)]`, a `<fn>_state_info(&self)` method returns a [`StateInfo`] describing where the machine is parked.
+#![no_std]
+
+#[cfg(feature = "std")]
+extern crate std;
+
+/// A value that is either ready with an output or asks to be polled again later.
+pub trait Awaitable {
+    type Output;
+
+    /// Returns `None` while the value is not ready yet.
+    fn into_ready(self) -> Option<Self::Output>;
+}
+
+impl<T> Awaitable for core::task::Poll<T> {
+    type Output = T;
+
+    fn into_ready(self) -> Option<T> {
+        match self {
+            core::task::Poll::Ready(v) => Some(v),
+            core::task::Poll::Pending => None,
+        }
+    }
+}
+
+/// `Err(WouldBlock)` is pending, any other result is ready.
+#[cfg(feature = "std")]
+impl<T> Awaitable for std::io::Result<T> {
+    type Output = std::io::Result<T>;
+
+    fn into_ready(self) -> Option<std::io::Result<T>> {
+        match self {
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => None,
+            res => Some(res),
+        }
+    }
+}
+
+/// Where a state machine is parked, as returned by a generated `<fn>_state_info` method.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StateInfo {
+    /// The current state number.
+    pub state: u64,
+    /// The `#[gentian_state(name = "..")]` name of the state, if any.
+    pub name: Option<&'static str>,
+    /// The source file of the statement the state resumes at.
+    pub file: &'static str,
+    /// The line of that statement, or `0` for a value that is not a state of the machine.
+    pub line: u32,
+}
+
+impl core::fmt::Display for StateInfo {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "state {}", self.state)?;
+        if let Some(name) = self.name {
+            write!(f, " ({})", name)?;
+        }
+        write!(f, " at {}:{}", self.file, self.line)
+    }
+}
@@ -1,3 +1,4 @@
+use crate::stmt::CoContext;
 use bae::FromAttributes;
 use quote::ToTokens;
 
@@ -5,6 +6,27 @@ use quote::ToTokens;
 pub struct GentianAttr {
     pub state: Option<syn::Expr>,
     pub ret_val: Option<syn::Expr>,
+    pub emit_buf: Option<syn::Expr>,
+    pub emit_offset: Option<syn::Expr>,
+    pub resume_arg: Option<syn::Expr>,
+    pub join_slots: Option<syn::Expr>,
+    pub future_slot: Option<syn::Expr>,
+    pub cx: Option<syn::Expr>,
+    pub pending: Option<syn::Expr>,
+    pub io_offset: Option<syn::Expr>,
+    pub budget: Option<syn::Expr>,
+    pub ticks: Option<syn::Expr>,
+    pub storage: Option<syn::Expr>,
+    pub poisoned: Option<syn::Expr>,
+    pub on_transition: Option<syn::Expr>,
+    pub state_type: Option<syn::Type>,
+    pub awaitable: Option<()>,
+    pub reset: Option<()>,
+    pub poison_on_panic: Option<()>,
+    pub checkpoint: Option<()>,
+    pub stable_states: Option<()>,
+    pub state_info: Option<()>,
+    pub tracing: Option<()>,
 }
 
 impl GentianAttr {
@@ -21,4 +43,31 @@ impl GentianAttr {
         }
         String::new()
     }
+
+    pub(crate) fn get_co_context(&self) -> CoContext {
+        CoContext {
+            emit_buf: self.emit_buf.clone(),
+            emit_offset: self.emit_offset.clone(),
+            resume_arg: self.resume_arg.clone(),
+            join_slots: self.join_slots.clone(),
+            future_slot: self.future_slot.clone(),
+            cx: self.cx.clone(),
+            pending: self.pending.clone(),
+            io_offset: self.io_offset.clone(),
+            budget: self.budget.clone(),
+            ticks: self.ticks.clone(),
+            storage: self.storage.clone(),
+            poisoned: self.poisoned.clone(),
+            on_transition: self.on_transition.clone(),
+            state_type: self.state_type.clone(),
+            awaitable: self.awaitable.is_some(),
+            reset: self.reset.is_some(),
+            poison_on_panic: self.poison_on_panic.is_some(),
+            checkpoint: self.checkpoint.is_some(),
+            stable_states: self.stable_states.is_some(),
+            state_info: self.state_info.is_some(),
+            tracing: self.tracing.is_some(),
+            ..Default::default()
+        }
+    }
 }
@@ -1,6 +1,9 @@
-use crate::stmt::{else_stmt, end_node_stmt, final_stmt, is_co_yield_or_co_return_expr, is_yield_or_return, nop_stmt, semi_token, start_node_stmt, start_stmt};
-use quote::ToTokens;
+use crate::stmt::{else_stmt, CoContext, SelectArm, end_node_stmt, final_stmt, is_co_yield_or_co_return_expr, is_yield_or_return, jump_stmt, nop_stmt, resume_stmt, run_defers_stmt, semi_token, start_node_stmt, start_stmt};
+use quote::{format_ident, ToTokens};
 use std::collections::{HashMap, HashSet};
+use proc_macro2::Span;
+use syn::spanned::Spanned;
+use std::ops::Range;
 use syn::Expr;
 use syn::Stmt;
 
@@ -19,18 +22,52 @@ impl LoopLabel {
     }
 }
 
+/// The nodes one statement was lowered to.
+pub struct StmtSpan {
+    pub before_idx: u32,
+    pub nodes: Range<u32>,
+    pub end_idx: u32,
+}
+
+impl StmtSpan {
+    pub(crate) fn new(before_idx: u32, nodes: Range<u32>, end_idx: u32) -> StmtSpan {
+        StmtSpan {
+            before_idx,
+            nodes,
+            end_idx,
+        }
+    }
+}
+
 pub trait CFG {
     fn new_cfg_graph() -> (Self, u32)
     where
         Self: Sized;
     fn add_cfg_edge(&mut self, a: u32, b: u32, stmt: Stmt);
+    /// Lowers a statement written by the user.
     fn proc_stmt(
         &mut self,
         stmt: &syn::Stmt,
         cur_idx: u32,
         final_idx: u32,
         loop_label_node_id: &mut Vec<LoopLabel>,
-    ) -> u32;
+    ) -> syn::Result<u32>;
+    /// Lowers a statement built by the lowering of another statement.
+    fn proc_lowered_stmt(
+        &mut self,
+        stmt: &syn::Stmt,
+        cur_idx: u32,
+        final_idx: u32,
+        loop_label_node_id: &mut Vec<LoopLabel>,
+    ) -> syn::Result<u32>;
+    /// The lowering shared by `proc_stmt` and `proc_lowered_stmt`.
+    fn lower_stmt(
+        &mut self,
+        stmt: &syn::Stmt,
+        cur_idx: u32,
+        final_idx: u32,
+        loop_label_node_id: &mut Vec<LoopLabel>,
+    ) -> syn::Result<u32>;
     fn proc_expr(
         &mut self,
         expr: &syn::Expr,
@@ -38,7 +75,7 @@ pub trait CFG {
         final_idx: u32,
         loop_label_node_id: &mut Vec<LoopLabel>,
         is_semi: bool,
-    ) -> u32;
+    ) -> syn::Result<u32>;
     fn figure_out_projections(&self) -> HashMap<usize, usize>;
 }
 
@@ -67,6 +104,28 @@ pub struct CFGraph {
     pub(crate) e: Vec<u32>,
     pub(crate) ne: Vec<u32>,
     pub(crate) in_degree: Vec<InDegree>,
+    pub(crate) co_ctx: CoContext,
+    /// `co_defer!` blocks in declaration order.
+    pub(crate) defers: Vec<syn::Block>,
+    /// How many of `defers` are active at each node.
+    pub(crate) defer_count: Vec<usize>,
+    /// The span of the user statement each node was lowered from.
+    pub(crate) node_spans: Vec<Span>,
+    pub(crate) cur_span: Span,
+    /// Set while a built statement is lowered, whose nodes keep the span of the user statement.
+    lowering: bool,
+    /// `#[state("label")]` labels of statements.
+    pub(crate) state_labels: Vec<(StmtSpan, syn::LitStr)>,
+    /// `#[gentian_state(name = "..")]` names of statements.
+    pub(crate) state_names: Vec<(StmtSpan, syn::LitStr)>,
+    /// Whether a `co_yield` binding takes the `resume_arg` parameter.
+    pub(crate) resume_bindings: bool,
+    /// Labels shown instead of the lowered statements of nodes in the DOT output.
+    pub(crate) node_labels: HashMap<u32, String>,
+    /// The number of loop back edges with a `budget` counter, `gentian_budget_<k>`.
+    pub(crate) budget_counters: usize,
+    /// How many statements took a place of each per statement `gentian_attr` parameter.
+    point_uses: HashMap<&'static str, usize>,
 }
 
 impl CFGraph {
@@ -77,6 +136,18 @@ impl CFGraph {
             e: vec![],
             ne: vec![],
             in_degree: vec![],
+            co_ctx: CoContext::default(),
+            defers: vec![],
+            defer_count: vec![],
+            node_spans: vec![],
+            cur_span: Span::call_site(),
+            lowering: false,
+            state_labels: vec![],
+            state_names: vec![],
+            resume_bindings: false,
+            node_labels: HashMap::new(),
+            budget_counters: 0,
+            point_uses: HashMap::new(),
         }
     }
     pub fn add_node(&mut self, node: Stmt) -> u32 {
@@ -86,6 +157,8 @@ impl CFGraph {
             h: u32::MAX,
         });
         self.in_degree.push(InDegree::new());
+        self.defer_count.push(self.defers.len());
+        self.node_spans.push(self.cur_span);
         tmp as u32
     }
 
@@ -104,6 +177,156 @@ impl CFGraph {
         self.edges.push(weight);
         tmp
     }
+
+    /// Starts a new state at the returned node, so code placed after it can return without
+    /// touching the state and be re-entered on the next call.
+    pub fn add_resume_point(&mut self, cur_idx: u32) -> u32 {
+        let idx = self.add_node(start_node_stmt());
+        self.add_cfg_edge(cur_idx, idx, resume_stmt());
+        idx
+    }
+
+    /// A copy of `co_ctx` whose parameter `name` is the place of the statement, which keeps its
+    /// progress while suspended. A tuple of places gives each statement using the parameter its
+    /// own place, in the order of the body; a single place serves a single statement.
+    fn point_ctx(
+        &mut self,
+        stmt: &Stmt,
+        name: &'static str,
+        param: fn(&mut CoContext) -> &mut Option<Expr>,
+    ) -> syn::Result<CoContext> {
+        let nth = self.point_uses.entry(name).or_insert(0);
+        let mut ctx = self.co_ctx.clone();
+        let place = param(&mut ctx);
+        match place {
+            Some(Expr::Tuple(places)) => match places.elems.iter().nth(*nth) {
+                Some(p) => *place = Some(p.clone()),
+                None => {
+                    return Err(syn::Error::new_spanned(
+                        stmt,
+                        format!(
+                            "`{}` has no place left for this statement, list one place per statement",
+                            name
+                        ),
+                    ))
+                }
+            },
+            Some(_) if *nth > 0 => {
+                return Err(syn::Error::new_spanned(
+                    stmt,
+                    format!(
+                        "`{}` is already used by another statement, give each statement its own place as in `{} = (self.a, self.b)`",
+                        name, name
+                    ),
+                ))
+            }
+            _ => {}
+        }
+        *nth += 1;
+        Ok(ctx)
+    }
+
+    /// Every arm polls from a state of its own and falls through to the next arm's state
+    /// while pending, so the arm bodies are regular CFG regions that may yield again.
+    fn proc_co_select(
+        &mut self,
+        arms: &[SelectArm],
+        cur_idx: u32,
+        final_idx: u32,
+        loop_label_node_id: &mut Vec<LoopLabel>,
+    ) -> syn::Result<u32> {
+        let end_idx = self.add_node(end_node_stmt());
+        let first_idx = self.add_resume_point(cur_idx);
+        let mut arm_idx = first_idx;
+        for (i, arm) in arms.iter().enumerate() {
+            let next_idx = if i + 1 < arms.len() {
+                Some(self.add_node(start_node_stmt()))
+            } else {
+                None
+            };
+            let poll_stmt =
+                crate::stmt::transform_co_select_arm(arm, &self.co_ctx, first_idx, next_idx);
+            let mut body_idx =
+                self.proc_lowered_stmt(&poll_stmt, arm_idx, final_idx, loop_label_node_id)?;
+            if let Some(next_idx) = next_idx {
+                self.add_jump(body_idx, next_idx);
+                arm_idx = next_idx;
+            }
+            for stmt in &arm.body.stmts {
+                body_idx = self.proc_stmt(stmt, body_idx, final_idx, loop_label_node_id)?;
+            }
+            self.add_cfg_edge(body_idx, end_idx, nop_stmt());
+        }
+        Ok(end_idx)
+    }
+
+    /// Registers a top level `co_defer!` block. It starts a new state, so every node of a state
+    /// has the same blocks active.
+    pub fn add_defer(&mut self, block: syn::Block, cur_idx: u32) -> u32 {
+        self.defers.push(block);
+        self.add_resume_point(cur_idx)
+    }
+
+    /// The active `co_defer!` blocks of `node`, latest first.
+    pub(crate) fn defers_to_string(&self, node: usize) -> String {
+        self.defers[..self.defer_count[node]]
+            .iter()
+            .rev()
+            .map(|b| b.to_token_stream().to_string())
+            .collect()
+    }
+
+    /// Adds a loop back edge. When `budget` is set, the edge counts the times it is taken in
+    /// a call with a counter of its own and suspends once it is over the budget.
+    pub fn add_back_edge(&mut self, cur_idx: u32, head_idx: u32) -> syn::Result<()> {
+        let mut cur_idx = cur_idx;
+        if self.co_ctx.budget.is_some() && cur_idx != u32::MAX {
+            let counter = format_ident!("gentian_budget_{}", self.budget_counters);
+            self.budget_counters += 1;
+            let check_stmt = crate::stmt::budget_check_stmt(&self.co_ctx, head_idx, &counter)?;
+            let check_idx = self.add_node(check_stmt);
+            self.add_cfg_edge(cur_idx, check_idx, nop_stmt());
+            self.add_jump(check_idx, head_idx);
+            cur_idx = check_idx;
+        }
+        self.add_cfg_edge(cur_idx, head_idx, nop_stmt());
+        Ok(())
+    }
+
+    /// Adds a jump to `final_idx` from the nodes lowered since `first_node` and the sources of the
+    /// conditions added since `first_edge` that run the `co_defer!` blocks themselves.
+    pub fn add_defer_jumps(&mut self, first_node: usize, first_edge: usize, final_idx: u32) {
+        let run_defers = run_defers_stmt().to_token_stream().to_string();
+        let runs_defers = |stmt: &Stmt| stmt.to_token_stream().to_string().contains(&run_defers);
+        let mut sources = Vec::new();
+        for (idx, node) in self.nodes.iter().enumerate() {
+            if idx >= first_node && runs_defers(&node.val) {
+                sources.push(idx as u32);
+            }
+            let mut i = node.h;
+            while i != u32::MAX {
+                if i as usize >= first_edge && runs_defers(&self.edges[i as usize]) {
+                    sources.push(idx as u32);
+                }
+                i = self.ne[i as usize];
+            }
+        }
+        for idx in sources {
+            self.add_jump(idx, final_idx);
+        }
+    }
+
+    /// Records that the statement at `cur_idx` may assign `state_of_node(target)` itself.
+    pub fn add_jump(&mut self, cur_idx: u32, target: u32) {
+        self.add_cfg_edge(cur_idx, target, jump_stmt());
+    }
+}
+
+/// The loop an unlabeled `break` or `continue` refers to.
+fn innermost_loop<'a>(loops: &'a [LoopLabel], expr: &Expr) -> syn::Result<&'a LoopLabel> {
+    loops
+        .last()
+        .ok_or_else(|| syn::Error::new_spanned(expr, "`break` and `continue` must be inside a loop"))
 }
 
 impl CFG for CFGraph {
@@ -127,23 +350,149 @@ impl CFG for CFGraph {
         cur_idx: u32,
         final_idx: u32,
         loop_label_node_id: &mut Vec<LoopLabel>,
-    ) -> u32 {
+    ) -> syn::Result<u32> {
+        if self.lowering {
+            return self.lower_stmt(stmt, cur_idx, final_idx, loop_label_node_id);
+        }
+        let outer_span = std::mem::replace(&mut self.cur_span, stmt.span());
+        let idx = self.lower_stmt(stmt, cur_idx, final_idx, loop_label_node_id);
+        self.cur_span = outer_span;
+        idx
+    }
+    fn proc_lowered_stmt(
+        &mut self,
+        stmt: &syn::Stmt,
+        cur_idx: u32,
+        final_idx: u32,
+        loop_label_node_id: &mut Vec<LoopLabel>,
+    ) -> syn::Result<u32> {
+        let outer = std::mem::replace(&mut self.lowering, true);
+        let idx = self.lower_stmt(stmt, cur_idx, final_idx, loop_label_node_id);
+        self.lowering = outer;
+        idx
+    }
+    fn lower_stmt(
+        &mut self,
+        stmt: &syn::Stmt,
+        cur_idx: u32,
+        final_idx: u32,
+        loop_label_node_id: &mut Vec<LoopLabel>,
+    ) -> syn::Result<u32> {
+        if let Some((label, stmt)) = crate::stmt::take_state_label(stmt)? {
+            let first_idx = self.nodes.len() as u32;
+            let idx = self.proc_stmt(&stmt, cur_idx, final_idx, loop_label_node_id)?;
+            let span = StmtSpan::new(cur_idx, first_idx..self.nodes.len() as u32, idx);
+            self.state_labels.push((span, label));
+            return Ok(idx);
+        }
+        if let Some((name, stmt)) = crate::stmt::take_state_name(stmt)? {
+            let first_idx = self.nodes.len() as u32;
+            let idx = self.proc_stmt(&stmt, cur_idx, final_idx, loop_label_node_id)?;
+            let span = StmtSpan::new(cur_idx, first_idx..self.nodes.len() as u32, idx);
+            self.state_names.push((span, name));
+            return Ok(idx);
+        }
         #[cfg(feature = "co_await")]
         {
             if crate::stmt::is_co_await_stmt(stmt){
-                let new_stmt = crate::stmt::transform_co_await_stmt(stmt);
-                return self.proc_stmt(&new_stmt,cur_idx,final_idx,loop_label_node_id);
+                let new_stmt = crate::stmt::transform_co_await_stmt(stmt, &self.co_ctx);
+                if crate::stmt::is_co_await_binding_stmt(stmt) {
+                    let resume_idx = self.add_resume_point(cur_idx);
+                    return self.proc_lowered_stmt(&new_stmt, resume_idx, final_idx, loop_label_node_id);
+                }
+                return self.proc_lowered_stmt(&new_stmt,cur_idx,final_idx,loop_label_node_id);
             }
         }
+        if crate::stmt::is_co_call_stmt(stmt, "co_try_await")
+            || crate::stmt::is_co_call_stmt(stmt, "co_await_io")
+        {
+            let new_stmt = if crate::stmt::is_co_call_stmt(stmt, "co_try_await") {
+                crate::stmt::transform_co_try_await_stmt(stmt, &self.co_ctx, final_idx)?
+            } else {
+                crate::stmt::transform_co_await_io_stmt(stmt, &self.co_ctx, final_idx)?
+            };
+            let resume_idx = self.add_resume_point(cur_idx);
+            let idx = self.proc_lowered_stmt(&new_stmt, resume_idx, final_idx, loop_label_node_id)?;
+            self.add_jump(idx, final_idx);
+            return Ok(idx);
+        }
+        if crate::stmt::is_co_io_all_stmt(stmt) {
+            let resume_idx = self.add_resume_point(cur_idx);
+            let ctx = self.point_ctx(stmt, "io_offset", |c| &mut c.io_offset)?;
+            let new_stmt = crate::stmt::transform_co_io_all_stmt(stmt, &ctx, final_idx)?;
+            let idx = self.add_node(new_stmt);
+            self.add_cfg_edge(resume_idx, idx, nop_stmt());
+            self.add_jump(idx, final_idx);
+            return Ok(idx);
+        }
+        if crate::stmt::get_co_defer_block(stmt)?.is_some() {
+            return Err(syn::Error::new_spanned(
+                stmt,
+                "`co_defer!` is only supported at the top level of the function body",
+            ));
+        }
+        if crate::stmt::is_bare_co_call_stmt(stmt, "co_wait_until") {
+            let resume_idx = self.add_resume_point(cur_idx);
+            let wait_stmt = crate::stmt::transform_co_wait_until_stmt(stmt, &self.co_ctx)?;
+            let idx = self.add_node(wait_stmt);
+            self.add_cfg_edge(resume_idx, idx, nop_stmt());
+            let wait = stmt.to_token_stream().to_string();
+            let wait = wait.trim_start_matches("co_").trim_end_matches(';').trim_end();
+            self.node_labels.insert(idx, wait.to_string());
+            return Ok(idx);
+        }
+        if crate::stmt::is_bare_co_call_stmt(stmt, "co_wait_ticks") {
+            let ctx = self.point_ctx(stmt, "ticks", |c| &mut c.ticks)?;
+            let (store_stmt, check_stmt) = crate::stmt::transform_co_wait_ticks_stmt(stmt, &ctx)?;
+            let store_idx = self.add_node(store_stmt);
+            self.add_cfg_edge(cur_idx, store_idx, nop_stmt());
+            let resume_idx = self.add_resume_point(store_idx);
+            let idx = self.add_node(check_stmt);
+            self.add_cfg_edge(resume_idx, idx, nop_stmt());
+            return Ok(idx);
+        }
+        if crate::stmt::is_co_call_stmt(stmt, "co_join") {
+            let ctx = self.point_ctx(stmt, "join_slots", |c| &mut c.join_slots)?;
+            let new_stmt = crate::stmt::transform_co_join_stmt(stmt, &ctx)?;
+            let resume_idx = self.add_resume_point(cur_idx);
+            return self.proc_lowered_stmt(&new_stmt, resume_idx, final_idx, loop_label_node_id);
+        }
+        if crate::stmt::is_co_await_future_stmt(stmt) {
+            let ctx = self.point_ctx(stmt, "future_slot", |c| &mut c.future_slot)?;
+            let (store_stmt, poll_stmt) = crate::stmt::transform_co_await_future_stmt(stmt, &ctx)?;
+            let store_idx = self.proc_lowered_stmt(&store_stmt, cur_idx, final_idx, loop_label_node_id)?;
+            let resume_idx = self.add_resume_point(store_idx);
+            return self.proc_lowered_stmt(&poll_stmt, resume_idx, final_idx, loop_label_node_id);
+        }
+        if let Some(arms) = crate::stmt::get_co_select_arms(stmt)? {
+            return self.proc_co_select(&arms, cur_idx, final_idx, loop_label_node_id);
+        }
+        if crate::stmt::is_co_emit_stmt(stmt) {
+            let ctx = self.point_ctx(stmt, "emit_offset", |c| &mut c.emit_offset)?;
+            let new_stmt = crate::stmt::transform_co_emit_stmt(stmt, &ctx)?;
+            return self.proc_lowered_stmt(&new_stmt, cur_idx, final_idx, loop_label_node_id);
+        }
+        if crate::stmt::is_co_yield_binding_stmt(stmt) {
+            let new_stmt = crate::stmt::transform_co_yield_binding_stmt(stmt, &self.co_ctx)?;
+            self.resume_bindings = true;
+            return self.proc_lowered_stmt(&new_stmt, cur_idx, final_idx, loop_label_node_id);
+        }
+        if crate::stmt::is_co_yield_from_stmt(stmt) {
+            let resume_idx = self.add_resume_point(cur_idx);
+            let idx = self.add_node(crate::stmt::transform_co_yield_from_stmt(stmt)?);
+            self.add_cfg_edge(resume_idx, idx, nop_stmt());
+            return Ok(idx);
+        }
         match stmt {
             Stmt::Local(_) => {
                 let idx = self.add_node(stmt.clone());
                 self.add_cfg_edge(cur_idx, idx, nop_stmt());
-                return idx;
-            }
-            Stmt::Item(_) => {
-                panic!("we don't support item for now.");
+                return Ok(idx);
             }
+            Stmt::Item(_) => Err(syn::Error::new_spanned(
+                stmt,
+                "items are not supported in the body of a generator",
+            )),
             Stmt::Expr(e) => {
                 return self.proc_expr(&e, cur_idx, final_idx, loop_label_node_id, false);
             }
@@ -160,7 +509,7 @@ impl CFG for CFGraph {
         final_idx: u32,
         loop_label_node_id: &mut Vec<LoopLabel>,
         is_semi: bool,
-    ) -> u32 {
+    ) -> syn::Result<u32> {
         let mut ret_idx = u32::MAX;
         match expr {
             Expr::If(e) => {
@@ -169,7 +518,7 @@ impl CFG for CFGraph {
                 self.add_cfg_edge(cur_idx, true_end_idx, Stmt::Expr(e.cond.as_ref().clone()));
                 for stmt in &e.then_branch.stmts {
                     true_end_idx =
-                        self.proc_stmt(stmt, true_end_idx, final_idx, loop_label_node_id);
+                        self.proc_stmt(stmt, true_end_idx, final_idx, loop_label_node_id)?;
                 }
                 self.add_cfg_edge(true_end_idx, end_idx, nop_stmt());
                 if let Some((_, cond)) = &e.else_branch {
@@ -181,7 +530,7 @@ impl CFG for CFGraph {
                         final_idx,
                         loop_label_node_id,
                         false,
-                    );
+                    )?;
                     self.add_cfg_edge(false_end_idx, end_idx, nop_stmt());
                 } else {
                     self.add_cfg_edge(cur_idx, end_idx, else_stmt());
@@ -207,9 +556,9 @@ impl CFG for CFGraph {
                 }
                 for stmt in &e.body.stmts {
                     true_end_idx =
-                        self.proc_stmt(stmt, true_end_idx, final_idx, loop_label_node_id);
+                        self.proc_stmt(stmt, true_end_idx, final_idx, loop_label_node_id)?;
                 }
-                self.add_cfg_edge(true_end_idx, before_enter_loop_idx, nop_stmt());
+                self.add_back_edge(true_end_idx, before_enter_loop_idx)?;
                 loop_label_node_id.pop();
                 ret_idx = false_st_idx;
             }
@@ -218,12 +567,12 @@ impl CFG for CFGraph {
                     let break_label = l.to_token_stream().to_string();
                     for l in loop_label_node_id {
                         if &l.name == &break_label {
-                            self.add_cfg_edge(cur_idx, l.start_idx.clone(), nop_stmt());
+                            self.add_back_edge(cur_idx, l.start_idx)?;
                         }
                     }
                 } else {
-                    let jump_idx = loop_label_node_id.last().unwrap().start_idx;
-                    self.add_cfg_edge(cur_idx, jump_idx, nop_stmt());
+                    let jump_idx = innermost_loop(loop_label_node_id, expr)?.start_idx;
+                    self.add_back_edge(cur_idx, jump_idx)?;
                 }
             }
             Expr::Break(e) => {
@@ -235,10 +584,40 @@ impl CFG for CFGraph {
                         }
                     }
                 } else {
-                    let jump_idx = loop_label_node_id.last().unwrap().end_idx;
+                    let jump_idx = innermost_loop(loop_label_node_id, expr)?.end_idx;
                     self.add_cfg_edge(cur_idx, jump_idx, nop_stmt());
                 }
             }
+            Expr::While(e) if crate::stmt::is_co_await_next_cond(&e.cond) => {
+                let before_enter_while_idx = self.add_node(nop_stmt());
+                let false_st_idx = self.add_node(end_node_stmt());
+                self.add_cfg_edge(cur_idx, before_enter_while_idx, nop_stmt());
+                let true_st_idx = self.add_resume_point(before_enter_while_idx);
+                let label = match &e.label {
+                    Some(l) => l.name.to_token_stream().to_string(),
+                    None => String::from(""),
+                };
+                loop_label_node_id.push(LoopLabel::new(true_st_idx, false_st_idx, label));
+                let next_stmt = crate::stmt::transform_co_await_next_cond(
+                    &e.cond,
+                    &self.co_ctx,
+                    false_st_idx,
+                )?;
+                let mut true_end_idx = self.proc_lowered_stmt(
+                    &next_stmt,
+                    true_st_idx,
+                    final_idx,
+                    loop_label_node_id,
+                )?;
+                self.add_jump(true_end_idx, false_st_idx);
+                for stmt in &e.body.stmts {
+                    true_end_idx =
+                        self.proc_stmt(stmt, true_end_idx, final_idx, loop_label_node_id)?;
+                }
+                self.add_back_edge(true_end_idx, true_st_idx)?;
+                loop_label_node_id.pop();
+                ret_idx = false_st_idx;
+            }
             Expr::While(e) => {
                 let before_enter_while_idx = self.add_node(nop_stmt());
                 let true_st_idx = self.add_node(start_node_stmt());
@@ -262,9 +641,9 @@ impl CFG for CFGraph {
                 }
                 for stmt in &e.body.stmts {
                     true_end_idx =
-                        self.proc_stmt(stmt, true_end_idx, final_idx, loop_label_node_id);
+                        self.proc_stmt(stmt, true_end_idx, final_idx, loop_label_node_id)?;
                 }
-                self.add_cfg_edge(true_end_idx, before_enter_while_idx, nop_stmt());
+                self.add_back_edge(true_end_idx, before_enter_while_idx)?;
                 self.add_cfg_edge(before_enter_while_idx, false_st_idx, else_stmt());
                 loop_label_node_id.pop();
                 ret_idx = false_st_idx;
@@ -282,7 +661,7 @@ impl CFG for CFGraph {
             Expr::Block(e) => {
                 let mut cur_idx = cur_idx;
                 for stmt in &e.block.stmts {
-                    cur_idx = self.proc_stmt(stmt, cur_idx, final_idx, loop_label_node_id);
+                    cur_idx = self.proc_stmt(stmt, cur_idx, final_idx, loop_label_node_id)?;
                 }
                 ret_idx = cur_idx;
             }
@@ -310,7 +689,7 @@ impl CFG for CFGraph {
                 }
             }
         }
-        ret_idx
+        Ok(ret_idx)
     }
 
     fn figure_out_projections(&self) -> HashMap<usize, usize> {
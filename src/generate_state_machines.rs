@@ -1,11 +1,16 @@
 #![allow(dead_code)]
 use crate::control_flow_graph::CFGraph;
+use crate::control_flow_graph::StmtSpan;
 use crate::control_flow_graph::CFG;
-use crate::stmt::{nop_stmt, transform_stmt_to_string};
-use quote::ToTokens;
+use crate::stmt::{
+    get_co_defer_block, jump_stmt, nop_stmt, run_defers_stmt, state_of_node,
+    transform_stmt_to_string, transform_try_exprs, CoContext,
+};
+use quote::{format_ident, quote, quote_spanned, ToTokens};
 use std::collections::HashMap;
 use std::collections::HashSet;
-use syn::ItemFn;
+use std::collections::{BTreeMap, BTreeSet};
+use syn::{parse_quote, ItemFn};
 
 pub struct Generator {
     cfg_graph: CFGraph,
@@ -13,6 +18,8 @@ pub struct Generator {
     state_projections: HashMap<usize, usize>,
     predefined_stmt: HashSet<String>,
     unused_states: HashSet<usize>,
+    state_names: BTreeMap<usize, String>,
+    fn_name: String,
 }
 
 impl Generator {
@@ -30,29 +37,380 @@ impl Generator {
             state_projections: HashMap::new(),
             predefined_stmt,
             unused_states: HashSet::new(),
+            state_names: BTreeMap::new(),
+            fn_name: String::new(),
         }
     }
 
+    pub(crate) fn set_co_context(&mut self, ctx: CoContext) {
+        self.cfg_graph.co_ctx = ctx;
+    }
+
     pub fn gen_state_machines_tokenstream(
         &mut self,
         mut function: ItemFn,
         state_name: &str,
         return_default_value: &str,
     ) -> proc_macro2::TokenStream {
+        self.fn_name = function.sig.ident.to_string();
+        let state: syn::Expr = syn::parse_str(state_name).unwrap();
+        let poison_on_panic = self.cfg_graph.co_ctx.poison_on_panic;
+        // with `poison_on_panic` the machine runs on a copy, and the state holds a sentinel meanwhile.
+        let machine_state = if poison_on_panic {
+            "gentian_state_"
+        } else {
+            state_name
+        };
+        self.cfg_graph.co_ctx.state = Some(syn::parse_str(machine_state).unwrap());
+        self.cfg_graph.node_spans[0] = function.sig.ident.span();
+        self.cfg_graph.node_spans[self.final_node_idx as usize] = function.block.brace_token.span;
+        if let Err(err) = self.build_cfg(&function) {
+            return err.to_compile_error();
+        }
+        println!("[gentian] build control flow graph successful!");
+
+        if let Err(err) = self.build_state_projections() {
+            return err.to_compile_error();
+        }
+        match self.name_states() {
+            Ok(state_names) => self.state_names = state_names,
+            Err(err) => return err.to_compile_error(),
+        }
+        let state_consts = self.gen_state_consts(&function);
+        let state_info_fn = if self.cfg_graph.co_ctx.state_info {
+            Some(self.gen_state_info_fn(&function, &state))
+        } else {
+            None
+        };
+        let reset_fn = if self.cfg_graph.co_ctx.reset {
+            Some(self.gen_reset_fn(&function, &state))
+        } else {
+            None
+        };
+        let checkpoint_fns = if self.cfg_graph.co_ctx.checkpoint {
+            if !cfg!(feature = "serde") {
+                return syn::Error::new(
+                    function.sig.ident.span(),
+                    "`checkpoint` requires the `serde` feature of gentian",
+                )
+                .to_compile_error();
+            }
+            match self.gen_checkpoint_fns(&function, &state) {
+                Ok(fns) => Some(fns),
+                Err(err) => return err.to_compile_error(),
+            }
+        } else {
+            None
+        };
+        function.block = self.gen_state_machines(machine_state, return_default_value);
+        if poison_on_panic {
+            function.block = self.poison_on_panic(&function, &state);
+        }
+        let mut tokens = function.to_token_stream();
+        tokens.extend(state_consts);
+        tokens.extend(state_info_fn);
+        tokens.extend(reset_fn);
+        tokens.extend(checkpoint_fns);
+        tokens
+    }
+
+    /// Lowers the statements of the function body into the control flow graph.
+    fn build_cfg(&mut self, function: &ItemFn) -> syn::Result<()> {
         let mut cur_idx = 0u32;
         let mut loop_label_node_id = Vec::new();
         for i in &function.block.stmts {
-            cur_idx =
+            if let Some(block) = get_co_defer_block(i)? {
+                cur_idx = self.cfg_graph.add_defer(block, cur_idx);
+                continue;
+            }
+            // once a `co_defer!` is reached, `?` has to run it before returning.
+            let lowered = if self.cfg_graph.defers.is_empty() {
+                None
+            } else {
+                transform_try_exprs(i, &self.cfg_graph.co_ctx, self.final_node_idx)
+            };
+            let (first_node, first_edge) = (self.cfg_graph.nodes.len(), self.cfg_graph.edges.len());
+            cur_idx = self.cfg_graph.proc_stmt(
+                lowered.as_ref().unwrap_or(i),
+                cur_idx,
+                self.final_node_idx,
+                &mut loop_label_node_id,
+            )?;
+            if lowered.is_some() {
                 self.cfg_graph
-                    .proc_stmt(&i, cur_idx, self.final_node_idx, &mut loop_label_node_id);
+                    .add_defer_jumps(first_node, first_edge, self.final_node_idx);
+            }
         }
         self.cfg_graph
             .add_cfg_edge(cur_idx, self.final_node_idx, nop_stmt());
-        println!("[gentian] build control flow graph successful!");
+        Ok(())
+    }
+
+    /// Maps states to their `#[gentian_state(name = "..")]` names, which must be identifiers,
+    /// unique within the function and one per state.
+    fn name_states(&self) -> syn::Result<BTreeMap<usize, String>> {
+        let mut first_use: HashMap<String, &syn::LitStr> = HashMap::new();
+        let mut state_names = BTreeMap::new();
+        for (span, lit) in &self.cfg_graph.state_names {
+            let name = lit.value();
+            if syn::parse_str::<syn::Ident>(&name).is_err() {
+                return Err(syn::Error::new(
+                    lit.span(),
+                    format!("state name `{}` is not an identifier", name),
+                ));
+            }
+            if let Some(first) = first_use.insert(name.clone(), lit) {
+                let mut err =
+                    syn::Error::new(lit.span(), format!("duplicate state name `{}`", name));
+                err.combine(syn::Error::new(first.span(), "first used here"));
+                return Err(err);
+            }
+            let state = self.state_of_stmt(span);
+            if let Some(other) = state_names.insert(state, name.clone()) {
+                return Err(syn::Error::new(
+                    lit.span(),
+                    format!("`{}` names the same state as `{}`", name, other),
+                ));
+            }
+        }
+        Ok(state_names)
+    }
 
-        self.build_state_projections();
-        function.block = self.gen_state_machines(state_name, return_default_value);
-        function.to_token_stream()
+    /// A `<FN>_<NAME>` constant holding the number of every named state.
+    fn gen_state_consts(&self, function: &ItemFn) -> proc_macro2::TokenStream {
+        let vis = &function.vis;
+        let state_type = match &self.cfg_graph.co_ctx.state_type {
+            Some(ty) => ty.clone(),
+            None => parse_quote!(usize),
+        };
+        let consts = self.state_names.iter().map(|(state, name)| {
+            let ident = format_ident!(
+                "{}_{}",
+                function.sig.ident.to_string().to_uppercase(),
+                name.to_uppercase()
+            );
+            let state = syn::LitInt::new(&state.to_string(), proc_macro2::Span::call_site());
+            quote! {
+                #[allow(dead_code)]
+                #vis const #ident: #state_type = #state;
+            }
+        });
+        quote! {#(#consts)*}
+    }
+
+    /// `<fn>_state_info(&self)` describes the current state, with `file!()` and `line!()`
+    /// spanned to the first statement each state was built from.
+    fn gen_state_info_fn(&self, function: &ItemFn, state: &syn::Expr) -> proc_macro2::TokenStream {
+        let mut first_node: BTreeMap<usize, usize> = BTreeMap::new();
+        for (node, state) in self.state_projections.iter() {
+            let first = first_node.entry(*state).or_insert(*node);
+            *first = (*first).min(*node);
+        }
+        let arms = first_node.iter().map(|(state, node)| {
+            let span = self.cfg_graph.node_spans[*node];
+            let file = quote_spanned!(span=> ::core::file!());
+            let line = quote_spanned!(span=> ::core::line!());
+            let name = match self.state_names.get(state) {
+                Some(name) => quote! {::core::option::Option::Some(#name)},
+                None => quote! {::core::option::Option::None},
+            };
+            let state = syn::LitInt::new(&state.to_string(), proc_macro2::Span::call_site());
+            quote! {
+                #state => ::gentian_core::StateInfo {
+                    state: #state,
+                    name: #name,
+                    file: #file,
+                    line: #line,
+                },
+            }
+        });
+        let vis = &function.vis;
+        let name = format_ident!("{}_state_info", function.sig.ident);
+        quote! {
+            #[allow(dead_code)]
+            #vis fn #name(&self) -> ::gentian_core::StateInfo {
+                match #state {
+                    #(#arms)*
+                    state_ => ::gentian_core::StateInfo {
+                        state: state_ as u64,
+                        name: ::core::option::Option::None,
+                        file: ::core::file!(),
+                        line: 0,
+                    },
+                }
+            }
+        }
+    }
+
+    /// Runs the machine inside a closure on a copy of the state, while the state itself holds
+    /// a poisoned sentinel. A panic leaves the sentinel behind, and the next call panics or
+    /// returns the `poisoned` value instead of re-running half-finished code.
+    fn poison_on_panic(&self, function: &ItemFn, state: &syn::Expr) -> Box<syn::Block> {
+        let machine = &function.block;
+        let sentinel = syn::LitInt::new(
+            &(self.cfg_graph.nodes.len() + 2).to_string(),
+            proc_macro2::Span::call_site(),
+        );
+        let on_poisoned = match &self.cfg_graph.co_ctx.poisoned {
+            Some(poisoned) => quote! {return #poisoned;},
+            None => {
+                let msg = format!("`{}` resumed after panicking", function.sig.ident);
+                quote! {panic!(#msg);}
+            }
+        };
+        let ret_ty = match &function.sig.output {
+            syn::ReturnType::Default => quote! {},
+            syn::ReturnType::Type(_, ty) => quote! {-> #ty},
+        };
+        Box::new(parse_quote! {{
+            if #state == #sentinel {
+                #on_poisoned
+            }
+            let mut gentian_state_ = #state;
+            #state = #sentinel;
+            #[allow(clippy::redundant_closure_call)]
+            let ret_ = (|| #ret_ty #machine)();
+            #state = gentian_state_;
+            ret_
+        }})
+    }
+
+    /// `<fn>_save` serializes the state and the `storage` places as a `<Fn>Checkpoint` with a
+    /// field named after each place, and `<fn>_load` restores them after checking that the state
+    /// exists in this build of the machine. The type is declared in both bodies, as a macro on a
+    /// method can only add items to the `impl` block.
+    fn gen_checkpoint_fns(
+        &self,
+        function: &ItemFn,
+        state: &syn::Expr,
+    ) -> syn::Result<proc_macro2::TokenStream> {
+        let places = self.cfg_graph.co_ctx.storage_places();
+        let mut fields: Vec<syn::Ident> = Vec::new();
+        for place in &places {
+            let field = match place {
+                syn::Expr::Field(syn::ExprField {
+                    member: syn::Member::Named(field),
+                    ..
+                }) => Some(field.clone()),
+                syn::Expr::Path(path) => path.path.get_ident().cloned(),
+                _ => None,
+            };
+            match field {
+                Some(field) if field != "state" && !fields.contains(&field) => fields.push(field),
+                _ => {
+                    return Err(syn::Error::new_spanned(
+                        place,
+                        "`checkpoint` names each `storage` place after its field or variable, \
+                         which must be distinct and not `state`",
+                    ))
+                }
+            }
+        }
+        let params: Vec<syn::Ident> = (0..fields.len()).map(|i| format_ident!("P{}", i)).collect();
+        let camel: String = function
+            .sig
+            .ident
+            .to_string()
+            .split('_')
+            .map(|word| {
+                let mut chars = word.chars();
+                match chars.next() {
+                    Some(c) => c.to_ascii_uppercase().to_string() + chars.as_str(),
+                    None => String::new(),
+                }
+            })
+            .collect();
+        let name = format_ident!("{}Checkpoint", camel);
+        let checkpoint = quote! {
+            #[derive(::serde::Serialize, ::serde::Deserialize)]
+            struct #name<State #(, #params)*> {
+                state: State,
+                #(#fields: #params,)*
+            }
+        };
+        let holes = params.iter().map(|_| quote! {_});
+        let states: BTreeSet<usize> = self.state_projections.values().cloned().collect();
+        let states = states
+            .iter()
+            .map(|s| syn::LitInt::new(&s.to_string(), proc_macro2::Span::call_site()));
+        let msg = format!("`{}` has no state {{}}", function.sig.ident);
+        let vis = &function.vis;
+        let save = format_ident!("{}_save", function.sig.ident);
+        let load = format_ident!("{}_load", function.sig.ident);
+        Ok(quote! {
+            #[allow(dead_code)]
+            #vis fn #save<S: ::serde::Serializer>(
+                &self,
+                serializer: S,
+            ) -> ::core::result::Result<S::Ok, S::Error> {
+                #checkpoint
+                let checkpoint_ = #name {
+                    state: &#state,
+                    #(#fields: &#places,)*
+                };
+                ::serde::Serialize::serialize(&checkpoint_, serializer)
+            }
+
+            #[allow(dead_code)]
+            #vis fn #load<'de, D: ::serde::Deserializer<'de>>(
+                &mut self,
+                deserializer: D,
+            ) -> ::core::result::Result<(), D::Error> {
+                #checkpoint
+                let checkpoint_: #name<_ #(, #holes)*> =
+                    ::serde::Deserialize::deserialize(deserializer)?;
+                match checkpoint_.state {
+                    #(#states)|* => {}
+                    _ => {
+                        return ::core::result::Result::Err(::serde::de::Error::custom(
+                            ::core::format_args!(#msg, checkpoint_.state),
+                        ));
+                    }
+                }
+                #state = checkpoint_.state;
+                #(#places = checkpoint_.#fields;)*
+                ::core::result::Result::Ok(())
+            }
+        })
+    }
+
+    /// `<fn>_reset(&mut self)` runs the `co_defer!` blocks active in the current state and moves
+    /// the machine back to its start.
+    fn gen_reset_fn(&self, function: &ItemFn, state: &syn::Expr) -> proc_macro2::TokenStream {
+        let mut defers_of_state: BTreeMap<usize, usize> = BTreeMap::new();
+        for (node, state) in self.state_projections.iter() {
+            let count = defers_of_state.entry(*state).or_insert(0);
+            *count = (*count).max(self.cfg_graph.defer_count[*node]);
+        }
+        let mut states_of_defers: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        for (state, count) in defers_of_state {
+            if count != 0 {
+                states_of_defers.entry(count).or_default().push(state);
+            }
+        }
+        let mut arms = String::new();
+        for (count, states) in states_of_defers {
+            let states: Vec<String> = states.iter().map(|s| s.to_string()).collect();
+            let defers: String = self.cfg_graph.defers[..count]
+                .iter()
+                .rev()
+                .map(|b| b.to_token_stream().to_string())
+                .collect();
+            arms.push_str(&format!("{}=>{{{}}}", states.join("|"), defers));
+        }
+        let body: syn::Block = syn::parse_str(&format!(
+            "{{match {}{{{} _=>{{}}}} {}=0;}}",
+            state.to_token_stream(),
+            arms,
+            state.to_token_stream()
+        ))
+        .unwrap();
+        let vis = &function.vis;
+        let name = format_ident!("{}_reset", function.sig.ident);
+        quote! {
+            #[allow(dead_code)]
+            #vis fn #name(&mut self) #body
+        }
     }
 
     pub fn get_cfg_state_graph(&self) -> String {
@@ -68,11 +426,18 @@ impl Generator {
                 "{} [label=\"state:{}, {} \"]",
                 idx,
                 if let Some(v) = self.state_projections.get(&idx) {
-                    v.to_string()
+                    match self.state_names.get(v) {
+                        Some(name) => format!("{} ({})", v, name),
+                        None => v.to_string(),
+                    }
                 } else {
                     String::from("invalid")
                 },
-                node.val.to_token_stream().to_string().replace("\"", "\\\"")
+                match self.cfg_graph.node_labels.get(&(idx as u32)) {
+                    Some(label) => label.clone(),
+                    None => node.val.to_token_stream().to_string(),
+                }
+                .replace("\"", "\\\"")
             )
             .unwrap();
         }
@@ -96,7 +461,7 @@ impl Generator {
         dot_string
     }
 
-    fn build_state_projections(&mut self) {
+    fn build_state_projections(&mut self) -> syn::Result<()> {
         self.state_projections = self.cfg_graph.figure_out_projections();
         let mut unused_states = HashSet::new();
         let mut state_projections = self.state_projections.clone();
@@ -127,6 +492,124 @@ impl Generator {
             // zero state is now used.
             self.unused_states.remove(&0);
         }
+        if self.cfg_graph.co_ctx.stable_states {
+            self.stabilize_states()?;
+        }
+        Ok(())
+    }
+
+    /// The state a statement suspends in: the first state its nodes start, or else the state
+    /// it ends in.
+    fn state_of_stmt(&self, span: &StmtSpan) -> usize {
+        let before = self.state_projections.get(&(span.before_idx as usize));
+        for node in span.nodes.clone() {
+            if let Some(state) = self.state_projections.get(&(node as usize)) {
+                if Some(state) != before {
+                    return *state;
+                }
+            }
+        }
+        *self
+            .state_projections
+            .get(&(span.end_idx as usize))
+            .unwrap()
+    }
+
+    /// Renumbers every state but the start state by a hash of its `#[state("label")]` label,
+    /// or else of its statements, so editing one part of a function keeps the numbers of the
+    /// others. Two states that would share a number are reported.
+    fn stabilize_states(&mut self) -> syn::Result<()> {
+        let mut labels: HashMap<usize, syn::LitStr> = HashMap::new();
+        for (span, label) in &self.cfg_graph.state_labels {
+            let state = self.state_of_stmt(span);
+            if let Some(other) = labels.insert(state, label.clone()) {
+                let mut err = syn::Error::new(
+                    label.span(),
+                    format!(
+                        "`#[state(\"{}\")]` and `#[state(\"{}\")]` label the same state",
+                        other.value(),
+                        label.value()
+                    ),
+                );
+                err.combine(syn::Error::new(other.span(), "first label here"));
+                return Err(err);
+            }
+        }
+        let mut nodes_of_state: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        for (node, state) in self.state_projections.iter() {
+            nodes_of_state.entry(*state).or_default().push(*node);
+        }
+        // the first arm of the generated `match`, and the `poison_on_panic` sentinel.
+        let mut reserved: HashMap<usize, (String, Option<proc_macro2::Span>)> = HashMap::new();
+        reserved.insert(
+            self.cfg_graph.nodes.len() + 1,
+            (String::from("a reserved state"), None),
+        );
+        reserved.insert(
+            self.cfg_graph.nodes.len() + 2,
+            (String::from("a reserved state"), None),
+        );
+        let mut stable_ids: HashMap<usize, usize> = HashMap::new();
+        for (state, mut nodes) in nodes_of_state {
+            if state == 0 {
+                stable_ids.insert(0, 0);
+                continue;
+            }
+            let (key, what, span) = match labels.get(&state) {
+                Some(label) => (
+                    format!("label:{}", label.value()),
+                    format!("`#[state(\"{}\")]`", label.value()),
+                    label.span(),
+                ),
+                None => {
+                    nodes.sort();
+                    let mut key = String::new();
+                    for node in &nodes {
+                        key.push_str(
+                            &self.cfg_graph.nodes[*node]
+                                .val
+                                .to_token_stream()
+                                .to_string(),
+                        );
+                        let mut i = self.cfg_graph.nodes[*node].h;
+                        while i != u32::MAX {
+                            key.push_str(
+                                &self.cfg_graph.edges[i as usize]
+                                    .to_token_stream()
+                                    .to_string(),
+                            );
+                            i = self.cfg_graph.ne[i as usize];
+                        }
+                    }
+                    let what = format!(
+                        "the state starting with `{}`",
+                        self.cfg_graph.nodes[nodes[0]].val.to_token_stream()
+                    );
+                    (key, what, self.cfg_graph.node_spans[nodes[0]])
+                }
+            };
+            let id = stable_state_id(&strip_node_indices(&key));
+            if let Some((other, other_span)) = reserved.insert(id, (what.clone(), Some(span))) {
+                let mut err = syn::Error::new(
+                    span,
+                    format!(
+                        "{} and {} share the state id {}, label one of them with `#[state(\"..\")]`",
+                        other, what, id
+                    ),
+                );
+                if let Some(other_span) = other_span {
+                    err.combine(syn::Error::new(other_span, "the other state starts here"));
+                }
+                return Err(err);
+            }
+            stable_ids.insert(state, id);
+        }
+        for (_, state) in self.state_projections.iter_mut() {
+            *state = *stable_ids.get(state).unwrap();
+        }
+        // every projected state is used, and the old numbers may clash with the new ones.
+        self.unused_states.clear();
+        Ok(())
     }
 
     fn eliminate_single_state(&self, mut node: usize, mut state: usize) -> usize {
@@ -166,13 +649,134 @@ impl Generator {
         state
     }
 
+    /// Runs the active `co_defer!` blocks of `node` after the value of its `return` is evaluated.
+    fn splice_defers(&self, node: usize, return_str: &str) -> String {
+        let defers = self.cfg_graph.defers_to_string(node);
+        if defers.is_empty() {
+            return return_str.to_string();
+        }
+        let value = return_str
+            .trim_start_matches("return")
+            .trim()
+            .trim_end_matches(';');
+        if value.is_empty() {
+            format!("{}return;", defers)
+        } else {
+            format!("let ret_ = {};{}return ret_;", value, defers)
+        }
+    }
+
+    /// Every `state = N` assignment of the dispatcher, from `from`, goes through here.
+    fn set_state(&self, from: usize, to: usize) -> String {
+        let state = self.cfg_graph.co_ctx.state.as_ref().unwrap();
+        format!(
+            "{}={};{}",
+            state.to_token_stream(),
+            to,
+            self.observe_transition(from, to)
+        )
+    }
+
+    /// The `tracing` event and the `on_transition` hook of a transition, nothing without them.
+    fn observe_transition(&self, from: usize, to: usize) -> String {
+        let mut observe = String::new();
+        if self.cfg_graph.co_ctx.tracing {
+            observe.push_str(&format!(
+                "::tracing::trace!(function = \"{}\", from = {}, to = {}, \"state transition\");",
+                self.fn_name, from, to
+            ));
+        }
+        if let Some(hook) = &self.cfg_graph.co_ctx.on_transition {
+            let hook = hook.to_token_stream().to_string();
+            // `Self::record` is a method of the receiver, anything else is called as is.
+            let hook = match hook.strip_prefix("Self :: ") {
+                Some(method) => format!("self.{}", method),
+                None => hook,
+            };
+            observe.push_str(&format!("{}({}, {});", hook, from, to));
+        }
+        observe
+    }
+
+    /// Resolves the `state_of_node` placeholders of a lowered statement of state `cur_state`,
+    /// turning `state = placeholder;` into a `set_state`.
+    fn resolve_placeholders(&self, stmt_str: &str, cur_state: usize) -> String {
+        let prefix = state_of_node(0).to_string();
+        let prefix = prefix.trim_end_matches("0_");
+        let assign = format!(
+            "{} = ",
+            self.cfg_graph
+                .co_ctx
+                .state
+                .as_ref()
+                .unwrap()
+                .to_token_stream()
+        );
+        let mut out = String::new();
+        let mut rest = stmt_str;
+        while let Some(pos) = rest.find(prefix) {
+            let (head, placeholder) = rest.split_at(pos);
+            let digits = &placeholder[prefix.len()..];
+            let len = digits.find(|c: char| !c.is_ascii_digit()).unwrap();
+            let node: usize = digits[..len].parse().unwrap();
+            let state = self.eliminate_single_state(node, self.state_projections[&node]);
+            let tail = digits[len..].trim_start_matches('_').trim_start();
+            match (head.strip_suffix(&assign), tail.strip_prefix(';')) {
+                (Some(head), Some(tail)) => {
+                    out.push_str(head);
+                    out.push_str(&self.set_state(cur_state, state));
+                    rest = tail;
+                }
+                _ => {
+                    out.push_str(head);
+                    out.push_str(&state.to_string());
+                    rest = tail;
+                }
+            }
+        }
+        out.push_str(rest);
+        out
+    }
+
     fn gen_state_machines(&self, state_name: &str, return_default_value: &str) -> Box<syn::Block> {
         let state_name = format!("{}", state_name);
         let else_stmt = String::from("else_stmt");
+        let resume_stmt = String::from("resume_stmt");
+        let jump_stmt = jump_stmt();
         let project_to_state: &HashMap<usize, usize> = &self.state_projections;
         let mut discovered: HashSet<usize> = HashSet::new();
+        let mut prologue = String::new();
+        if self.cfg_graph.co_ctx.tracing {
+            prologue.push_str(&format!(
+                "let _gentian_span = ::tracing::trace_span!(\"{}\", state = {}).entered();",
+                self.fn_name, state_name
+            ));
+        }
+        if let Some(arg) = self
+            .cfg_graph
+            .co_ctx
+            .resume_arg
+            .as_ref()
+            .filter(|_| self.cfg_graph.resume_bindings)
+        {
+            // `co_yield` bindings take the resume argument at most once per call. Without them
+            // the parameter is left to the body.
+            prologue.push_str(&format!(
+                "#[allow(unused_mut, unused_variables)] let mut resume_arg_ = ::core::option::Option::Some({});",
+                arg.to_token_stream()
+            ));
+        }
+        for k in 0..self.cfg_graph.budget_counters {
+            // the times each loop back edge was taken in this call, see `budget_check_stmt`.
+            prologue.push_str(&format!("let mut gentian_budget_{}: usize = 0;", k));
+        }
+        if !self.state_names.is_empty() {
+            // the names are kept as doc comments on the arms of the expanded code.
+            prologue.push_str("#[allow(unused_doc_comments)]");
+        }
         let mut loops = String::from(format!(
-            "{{'genloop: loop{{ \nmatch {}\n{{ \n{}=>{{\nbreak 'genloop;\n",
+            "{{{}'genloop: loop{{ \nmatch {}\n{{ \n{}=>{{\nbreak 'genloop;\n",
+            prologue,
             state_name,
             self.cfg_graph.nodes.len() + 1
         ));
@@ -189,17 +793,28 @@ impl Generator {
                 let is_unused_state = self.unused_states.contains(cur_state);
                 if discovered.insert(*cur_state) {
                     if !is_unused_state {
-                        loops.push_str(&format!("}}\n{}=>{{", cur_state));
+                        let doc = match self.state_names.get(cur_state) {
+                            Some(name) => format!("#[doc = \" state `{}`\"]", name),
+                            None => String::new(),
+                        };
+                        loops.push_str(&format!("}}\n{}{}=>{{", doc, cur_state));
                     }
                 }
                 if !is_unused_state {
                     let is_predefined_stmt: bool = self.predefined_stmt.contains(&stmt_str);
                     if !is_predefined_stmt && !is_yield_or_return {
-                        loops.push_str(&stmt_str);
+                        let stmt_str = self.resolve_placeholders(&stmt_str, *cur_state);
+                        loops.push_str(&stmt_str.replace(
+                            &run_defers_stmt().to_token_stream().to_string(),
+                            &self.cfg_graph.defers_to_string(node),
+                        ));
                     } else if node as u32 == self.final_node_idx {
                         // out of the loop
                         loops.push_str(&format!("break 'genloop;",));
                     }
+                    // the fallthrough is assigned before the conditions, but only observed once
+                    // none of them is taken.
+                    let mut observe_fallthrough = String::new();
                     let mut i = self.cfg_graph.nodes[node].h;
                     while i != u32::MAX {
                         let next_node = self.cfg_graph.e[i as usize] as usize;
@@ -212,36 +827,59 @@ impl Generator {
                         //     println!("after eliminate unused state:{}->{}",cur_state,next_state_eliminated);
                         // }
                         let next_state = &next_state_eliminated;
-                        if e != nop_stmt() {
-                            assert_ne!(next_state, cur_state);
+                        if e == jump_stmt {
+                            // the statement itself assigns the state, see `state_of_node`.
+                        } else if e != nop_stmt() {
+                            // `next_state` may equal `cur_state` when a branch only jumps back
+                            // to the loop head that starts this state, e.g. `if c { continue; }`.
                             let cond = e.to_token_stream().to_string();
-                            if cond != else_stmt {
+                            if cond != else_stmt && cond != resume_stmt {
+                                // a `?` of the condition may finish the machine itself.
+                                let cond = self.resolve_placeholders(&cond, *cur_state).replace(
+                                    &run_defers_stmt().to_token_stream().to_string(),
+                                    &self.cfg_graph.defers_to_string(node),
+                                );
                                 // if cond{state=next_state;continue 'genloop;}
                                 loops.push_str(&format!(
-                                    "if {}{{ {}={};continue 'genloop;}}",
-                                    cond, state_name, next_state
+                                    "if {}{{ {}continue 'genloop;}}",
+                                    cond,
+                                    self.set_state(*cur_state, *next_state)
                                 ));
                             } else {
                                 // state=next_state;
                                 loops.push_str(&format!("{}={};", state_name, next_state));
+                                observe_fallthrough =
+                                    self.observe_transition(*cur_state, *next_state);
                             }
                         } else if is_yield_or_return {
-                            loops.push_str(&format!("{}={};", state_name, next_state));
-                            loops.push_str(&stmt_str);
+                            loops.push_str(&self.set_state(*cur_state, *next_state));
+                            if next_node as u32 == self.final_node_idx {
+                                loops.push_str(&self.splice_defers(node, &stmt_str));
+                            } else {
+                                loops.push_str(&stmt_str);
+                            }
                         } else if next_state != cur_state {
-                            loops.push_str(&format!("{}={};", state_name, next_state));
+                            loops.push_str(&self.set_state(*cur_state, *next_state));
+                            if next_node as u32 == self.final_node_idx {
+                                loops.push_str(&self.cfg_graph.defers_to_string(node));
+                            }
                         }
                         i = self.cfg_graph.ne[i as usize];
                     }
+                    loops.push_str(&observe_fallthrough);
                 }
             }
-            let mut i = self.cfg_graph.nodes[node as usize].h;
-            while i != u32::MAX {
-                let next_node = self.cfg_graph.e[i as usize];
-                if !visit_set.contains(&next_node) {
-                    q.push(next_node);
+            // jump targets are visited last, so the rest of the current state stays in one arm.
+            for jump in [true, false] {
+                let mut i = self.cfg_graph.nodes[node as usize].h;
+                while i != u32::MAX {
+                    let next_node = self.cfg_graph.e[i as usize];
+                    let is_jump = self.cfg_graph.edges[i as usize] == jump_stmt;
+                    if is_jump == jump && !visit_set.contains(&next_node) {
+                        q.push(next_node);
+                    }
+                    i = self.cfg_graph.ne[i as usize];
                 }
-                i = self.cfg_graph.ne[i as usize];
             }
         }
         if return_default_value.is_empty() {
@@ -255,3 +893,27 @@ impl Generator {
         Box::new(syn::parse_str(&loops).unwrap())
     }
 }
+
+/// FNV-1a, kept below `i32::MAX` and away from the start state, so ids fit any state type
+/// that holds a `u32` and don't change between compiler versions.
+fn stable_state_id(key: &str) -> usize {
+    let mut hash: u32 = 0x811c9dc5;
+    for b in key.bytes() {
+        hash ^= b as u32;
+        hash = hash.wrapping_mul(0x01000193);
+    }
+    (hash & 0x7fff_ffff).max(1) as usize
+}
+
+/// Drops the node indices of `state_of_node` placeholders, which shift with every edit.
+fn strip_node_indices(key: &str) -> String {
+    let prefix = "gentian_state_of_node_";
+    let mut out = String::new();
+    let mut rest = key;
+    while let Some(pos) = rest.find(prefix) {
+        out.push_str(&rest[..pos + prefix.len()]);
+        rest = rest[pos + prefix.len()..].trim_start_matches(|c: char| c.is_ascii_digit());
+    }
+    out.push_str(rest);
+    out
+}
@@ -1,18 +1,59 @@
 //! gentian is a proc macro that transforms generators to state machines.
 //! Currently it supports loop statements, while statements, if statements, and the extended syntax for using `co_yield` and `co_return` and `return` in these statements.
 //! # `gentian_attr` attribute of a function
-//!  It has two kinds of parameters,
+//!  It has two main kinds of parameters,
 //!  * `state` represents the state currently used to maintain the automaton.
 //!  * `ret_val` represents the default return value of the function, which is usually used for the result returned by calling again after the state machine ends.
 //!
+//!  The other parameters configure the statements described below,
+//!  * `pending` is the value returned while a `co_await` style statement is suspended, `Poll::Pending` by default.
+//!  * `awaitable` makes `co_await` accept any type implementing `gentian_core::Awaitable`.
+//!  * `resume_arg` is the parameter that `co_yield` expressions evaluate to.
+//!  * `join_slots` keeps the values of a `co_join` statement.
+//!  * `future_slot` and `cx` are the storage and the `Context` used to await futures.
+//!  * `io_offset` keeps the progress of `co_read_exact` and `co_write_all` statements.
+//!  * `emit_buf` and `emit_offset` are the buffer and the progress of a `co_emit` statement.
+//!  * `ticks` keeps the remaining count of a `co_wait_ticks` statement.
+//!
+//!  `join_slots`, `future_slot`, `io_offset`, `emit_offset` and `ticks` keep the progress of a statement while it is suspended, so each statement needs a place of its own.
+//!  With several statements, such as two `co_join`s, the parameter lists one place per statement in the order of the body, as in `ticks=(self.blink_left, self.pause_left)`.
+//!  A single place used by more than one statement is a compile error.
+//!
+//!  * `reset` generates a `<fn>_reset(&mut self)` method, see `co_defer!`.
+//!  * `poison_on_panic` and `poisoned` guard against resuming after a panic, see below.
+//!  * `state_info` generates a method that tells where the machine is parked, see below.
+//!  * `stable_states` numbers the states by labels or hashes instead of by their order, see below.
+//!  * `checkpoint` generates methods that save and load the machine through serde, see below.
+//!  * `storage` lists the places that `checkpoint` saves along with the state, see below.
+//!  * `budget` limits the iterations of each loop back edge in a single call, see below.
+//!  * `on_transition` is called on every state transition, see below.
+//!  * `state_type` is the type of the state, for the constants of named states, see below.
+//!  * `tracing` logs every call and transition through the `tracing` crate, see below.
+//!
 //! # `co_yield` or `co_return` statement
 //!  This divides into three logical steps:
 //!  * `co_yield` or `co_return` save the current state of the coroutine.
 //!  * The resume point is defined immediately following the statement.
 //!  * Same as rust `return` semantics, it returns from the function immediately.
 //!
+//! # Resume arguments
+//!  With `resume_arg=<parameter>` in `gentian_attr`, the value of a `co_yield` expression is the
+//!  argument the function is called with when it resumes.
+//!  ````ignore
+//!  #[gentian_attr(state=self.state, ret_val=None, resume_arg=input)]
+//!  fn feed(&mut self, input: u8) -> Option<Frame> {
+//!      loop {
+//!          let byte = co_yield(None);
+//!          ...
+//!      }
+//!  }
+//!  ````
+//!  It works for `let` bindings and assignments. Like any local in a generator body, a binding is only visible until the next branch or resume point.
+//!  * Without such bindings, the parameter is left alone, so the body can use or move each call's argument directly.
+//!  * With them, the bindings take the argument, so the body should not also move it.
+//!
 //! # `co_await` statement
-//!  It's a syntax sugar for `co_yield` or `co_return` with `std::task::Poll`.
+//!  It's a syntax sugar for `co_yield` or `co_return` with `core::task::Poll`.
 //!  ````ignore
 //!  co_await(some_poll_func());
 //!  ````
@@ -30,6 +71,246 @@
 //!  * `co_await` save the current state of the coroutine.
 //!  * The resume point is defined immediately following the statement and if and only if the waited poll function is ready.
 //!
+//!  With `awaitable` in `gentian_attr`, any type implementing `gentian_core::Awaitable` can be awaited, and `pending`
+//!  chooses what the function returns meanwhile.
+//!
+//!  Used in a `let` binding, a pattern binding or an assignment, `co_await` binds the value inside `Poll::Ready`.
+//!  ````ignore
+//!  let n = co_await(self.inner.poll_read(cx, buf));
+//!  let (len, addr) = co_await(self.socket.poll_recv_from(cx, buf));
+//!  self.len = co_await(self.inner.poll_read(cx, buf));
+//!  ````
+//!  * This changes the assignment form: `x = co_await(p);` used to assign the whole `Poll<T>` to `x`, and now assigns the value inside `Poll::Ready`.
+//!    See `CHANGELOG.md` for migrating code that matched on the assigned `Poll`.
+//!
+//! # `co_try_await` statement
+//!  It awaits a `Poll<Result<T, E>>` and forwards errors like `?`.
+//!  ````ignore
+//!  let n = co_try_await(self.inner.poll_read(cx, buf));
+//!  ````
+//!  * While the poll is pending, the function returns `Poll::Pending` and polls again on the next call.
+//!  * On `Poll::Ready(Ok(t))` the value `t` is bound.
+//!  * On `Poll::Ready(Err(e))` the function returns `Poll::Ready(Err(e.into()))` and the state machine ends.
+//!
+//! # `co_await_io` statement
+//!  It calls a non-blocking operation, such as `read` on a mio socket, that reports `io::ErrorKind::WouldBlock` instead of `Poll::Pending`.
+//!  ````ignore
+//!  let n = co_await_io(self.socket.read(&mut self.buf));
+//!  ````
+//!  * While the operation fails with `WouldBlock`, the function returns its `pending` value and calls it again on the next call.
+//!  * On `Ok(v)` the value `v` is bound.
+//!  * On any other error the function returns `Poll::Ready(Err(e.into()))` and the state machine ends.
+//!  * The result goes through `gentian_core::Awaitable`, so the crate depends on `gentian-core` with its `std` feature.
+//!    Any `Awaitable` whose output is a `Result` can be awaited the same way.
+//!
+//! # `co_read_exact` and `co_write_all` statements
+//!  They transfer a whole buffer through `poll_read` or `poll_write` of an `Unpin` reader or writer, such as `futures::io::AsyncRead`.
+//!  ````ignore
+//!  #[gentian_attr(state=self.state, io_offset=self.io_pos)]
+//!  ...
+//!  co_read_exact(self.stream, cx, &mut self.header);
+//!  co_write_all(self.stream, cx, &self.response);
+//!  ````
+//!  * The number of bytes already transferred is kept in `io_offset`, a `usize` place, so progress survives any number of `Poll::Pending` results.
+//!  * The resume point is defined immediately following the statement and is reached once the whole buffer is transferred.
+//!  * An error, or a read or write of zero bytes, makes the function return `Poll::Ready(Err(e.into()))` and the state machine ends.
+//!
+//! # `co_await_next` loop
+//!  It polls a `Poll<Option<T>>`, such as a child stream's `poll_next`, in the condition of a `while let` loop.
+//!  ````ignore
+//!  while let Some(item) = co_await_next(self.rx.poll_next(cx)) {
+//!      ...
+//!  }
+//!  ````
+//!  * While the poll is pending, the function returns `Poll::Pending` and polls again on the next call.
+//!  * On `Poll::Ready(Some(item))` the loop body runs with `item` bound.
+//!  * On `Poll::Ready(None)` the loop exits.
+//!
+//! # `co_wait_until` statement
+//!  It suspends until a condition holds, in place of `while !cond { co_yield(Poll::Pending); }`.
+//!  ````ignore
+//!  co_wait_until(self.ready());
+//!  ````
+//!  * The condition is checked on arrival and again on every following call, in a wait state of its own.
+//!  * While it is false, the function returns the `pending` value without changing the state.
+//!  * In `get_cfg_state_graph`, the wait state is the single node `wait_until(cond)`.
+//!
+//! # `co_wait_ticks` statement
+//!  It suspends for a number of calls, for generators that are driven once per tick.
+//!  ````ignore
+//!  #[gentian_attr(state=self.state, ret_val=None, pending=None, ticks=self.ticks_left)]
+//!  ...
+//!  co_wait_ticks(3);
+//!  ````
+//!  * The count is stored in `ticks`, a place of an unsigned integer type, when the statement is reached, and every call including that one spends one tick.
+//!  * The function returns the `pending` value for `n` calls, then continues on the next one, also inside loops and branches.
+//!  * Waiting until tick `t` is `co_wait_until(self.tick >= t)`.
+//!
+//! # `co_select!` statement
+//!  It races several poll expressions.
+//!  ````ignore
+//!  co_select! {
+//!      n = self.upstream.poll_read(cx, buf) => { ... }
+//!      () = self.shutdown.poll(cx) => { ... }
+//!  }
+//!  ````
+//!  * Every arm is polled in order on each call, and the first one that is ready runs with its pattern bound to the value inside `Poll::Ready`.
+//!  * When none of them is ready, the function returns `Poll::Pending`.
+//!  * The arm bodies may contain further resume points; the statement after `co_select!` runs once the chosen arm is done.
+//!
+//! # `co_join` statement
+//!  It waits for several poll expressions to all complete.
+//!  ````ignore
+//!  #[gentian_attr(state=self.state, join_slots=self.flushed)]
+//!  ...
+//!  let (a, b) = co_join(self.reader.poll_flush(cx), self.writer.poll_flush(cx));
+//!  ````
+//!  * `join_slots` is a tuple of `Option`s, one per expression, which keeps the values that are already ready.
+//!  * Each call polls only the expressions that are not ready yet, and returns `Poll::Pending` while any of them is pending.
+//!  * Once every expression is ready, the slots are emptied and the tuple of values is bound.
+//!
+//! # Awaiting futures
+//!  `co_await_future(fut)`, or `fut.await`, awaits an ordinary `Future` inside a poll function.
+//!  ````ignore
+//!  #[gentian_attr(state=self.state, future_slot=(self.sleep, self.lookup), cx=cx)]
+//!  fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<Option<Tick>> {
+//!      ...
+//!      tokio::time::sleep(d).await;
+//!      let addr = co_await_future(self.resolver.lookup(host));
+//!  }
+//!  ````
+//!  * Every await has a place of its own in `future_slot`, an `Option<Pin<Box<F>>>` field where `F` is the type of its future,
+//!    such as `tokio::time::Sleep`, or `dyn Future<Output = T>` when the type can't be named.
+//!  * The future is pinned with `alloc::boxed::Box::pin`, which works in `std` and `no_std` crates alike.
+//!  * It is polled with `cx` (default `cx`) and the function returns `Poll::Pending` until it is ready.
+//!  * The output is bound like the value of `co_await`, and the slot is emptied.
+//!
+//! # `co_yield_from` statement
+//!  It delegates to a child iterator, such as another generator stored in a field.
+//!  ````ignore
+//!  co_yield_from(self.child);
+//!  ````
+//!  * Each call resumes the child once and returns its value while it is `Some`, so the function must return the child's `Option<Item>`.
+//!  * The delegation is a single state; the resume point following the statement is reached once the child returns `None`.
+//!
+//! # `co_emit` statement
+//!  It hands out a byte slice to a `poll_read` shaped function across as many calls as needed.
+//!  ````ignore
+//!  #[gentian_attr(state=self.state, emit_buf=dst, emit_offset=self.emit_pos)]
+//!  ...
+//!  co_emit(self.plaintext);
+//!  ````
+//!  * Each call copies as much of the slice as fits into `emit_buf` (default `buf`) and returns `Poll::Ready(Ok(n))`.
+//!  * The copied offset is kept in `emit_offset`, which must be a `usize` place that survives between calls.
+//!  * The resume point is defined immediately following the statement and is reached once the whole slice has been emitted.
+//!
+//! # `co_defer!` statement
+//!  It registers a block that runs when the machine finishes, whether it returns, reaches the end of the body or fails in a `co_try_await` style statement.
+//!  ````ignore
+//!  #[gentian_attr(state=self.state, ret_val=Poll::Ready(Ok(0)), reset)]
+//!  ...
+//!  self.conn = Some(co_try_await(self.poll_connect(cx)));
+//!  co_defer! {
+//!      self.conn = None;
+//!  }
+//!  ````
+//!  * It is only supported at the top level of the function body, and a block runs only once the statement has been reached.
+//!  * Once a `co_defer!` is reached, a `?` on a `Result` in the body, outside of closures, also finishes the machine and runs the blocks before the error is returned.
+//!  * The blocks run latest first, after the value of a `return` is evaluated.
+//!  * With `reset`, `<fn>_reset` runs the blocks active in the current state and sets the state back to `0`, so they may only use `self`.
+//!
+//! # Named states
+//!  A statement that suspends can name the state it suspends in.
+//!  ````ignore
+//!  #[gentian_state(name = "await_header")]
+//!  self.len = co_await(self.header.poll(cx));
+//!  ````
+//!  * A constant `<FN>_<NAME>`, such as `POLL_READ_AWAIT_HEADER`, holds the number of the state.
+//!    It is a `usize` unless `state_type` in `gentian_attr` gives the type of the state, as in `state_type=u32`.
+//!  * The name shows up in the labels of `get_cfg_state_graph` and as a doc comment on the arm of the expanded code.
+//!  * Names are identifiers, unique within the function, and one per state, otherwise the macro reports an error on the name.
+//!
+//! # State introspection
+//!  With `state_info` in `gentian_attr`, a `<fn>_state_info(&self)` method returns a `gentian_core::StateInfo`.
+//!  ````ignore
+//!  log::warn!("connection stuck in {}", conn.poll_conn_state_info());
+//!  ````
+//!  * It holds the state number, the name given by `#[gentian_state(name = "..")]`, and the source file and line of the statement the state resumes at.
+//!  * A value that is not a state of the machine, such as a poisoned one, is reported with line `0`.
+//!
+//! # Stable state numbers
+//!  States are numbered in the order they are found, so adding one statement near the top renumbers every later state.
+//!  With `stable_states` in `gentian_attr`, every state but the start state `0` gets an id that only depends on the state itself.
+//!  ````ignore
+//!  #[gentian_attr(state=self.state, ret_val=None, stable_states)]
+//!  ...
+//!  #[state("handshake_done")]
+//!  co_yield(Some(Event::Connected));
+//!  ````
+//!  * The state a statement labeled `#[state("..")]` suspends in is numbered by a hash of the label.
+//!  * Any other state is numbered by a hash of its statements.
+//!  * Ids are below `i32::MAX`, so the state needs a type that holds a `u32`.
+//!  * If two states would share an id, such as two identical loops, the macro reports it and one of them needs a label.
+//!
+//! # Checkpoints
+//!  With the `serde` cargo feature of gentian and `checkpoint` in `gentian_attr`, the state and the `storage` places are persisted through serde,
+//!  so the crate using it needs a `serde` dependency with its `derive` feature.
+//!  ````ignore
+//!  #[gentian_attr(state=self.state, ret_val=Poll::Ready(0), storage=(self.host, self.disks), checkpoint)]
+//!  fn poll_provision(&mut self) -> Poll<u32> { ... }
+//!  ...
+//!  job.poll_provision_save(&mut serde_json::Serializer::new(&mut buf))?;
+//!  job.poll_provision_load(&mut serde_json::Deserializer::from_slice(&buf))?;
+//!  ````
+//!  * `<fn>_save` takes any `serde::Serializer`, and `<fn>_load` any `serde::Deserializer`.
+//!  * Loading fails if the saved state is not a state of this build of the machine, and then nothing is changed.
+//!  * The checkpoint is a generated `<Fn>Checkpoint` type, `PollProvisionCheckpoint` above, deriving `Serialize` and `Deserialize`,
+//!    with a `state` field and a field named after each place, here `host` and `disks`.
+//!  * The places must be fields or variables with distinct names other than `state`.
+//!  * Without the feature, `checkpoint` is an error.
+//!
+//! # Poisoning
+//!  With `poison_on_panic` in `gentian_attr`, the machine runs on a copy of the state while the state itself holds a poisoned sentinel, and the copy is written back once the call returns.
+//!  ````ignore
+//!  #[gentian_attr(state=self.state, ret_val=None, poison_on_panic, poisoned=Some(Event::Poisoned))]
+//!  ````
+//!  * If user code panics inside a state, the sentinel stays, so the next call doesn't re-run half-finished code.
+//!  * Calling a poisoned machine panics, or returns the `poisoned` value when it is given.
+//!  * `<fn>_reset` clears the poison.
+//!
+//! # Cooperative budget
+//!  With `budget=N` and `cx` in `gentian_attr`, every back edge of the control flow graph, the end of a loop body as well as each `continue`,
+//!  counts the times it is taken, and the counts start from zero on each call.
+//!  ````ignore
+//!  #[gentian_attr(state=self.state, ret_val=Poll::Ready(0), cx=cx, budget=128)]
+//!  ````
+//!  * Once one back edge is taken more than `N` times in a call, `cx.waker().wake_by_ref()` is called and the function returns the `pending` value.
+//!  * The next call resumes at the head of that loop, so a long loop no longer starves the executor.
+//!
+//! # Transition hook
+//!  With `on_transition` in `gentian_attr`, the generated code calls the hook with the old and the new state on every transition.
+//!  ````ignore
+//!  #[gentian_attr(state=self.state, ret_val=Poll::Ready(0), on_transition=Self::record)]
+//!  ````
+//!  * `Self::record` is called as `self.record(from, to)`, any other path as a free function `record(from, to)`.
+//!  * A transition is reported once it is taken, so the `to` of one call is the `from` of the next.
+//!  * Without `on_transition` no call is generated.
+//!
+//! # Tracing
+//!  With `tracing` in `gentian_attr`, the generated dispatcher logs its transitions through the [`tracing`](https://docs.rs/tracing) crate,
+//!  which the crate using the macro then has to depend on.
+//!  ````ignore
+//!  #[gentian_attr(state=self.state, ret_val=Poll::Ready(0), tracing)]
+//!  ````
+//!  * Every call enters a `trace_span!` named after the function, with the `state` it resumes at.
+//!  * Every `state = N` assignment emits a `trace!` event with the `function` name and the `from` and `to` states.
+//!  * It is chosen per function, so functions without it and crates without a `tracing` dependency are left alone.
+//!
+//! # `no_std`
+//!  The generated code names `Poll`, `Option` and `Result` by their full `::core` paths, so they don't need to be imported,
+//!  and generators can be used in `#![no_std]` crates. Only awaiting futures allocates, through the `alloc` crate,
+//!  so the target needs a global allocator.
+//!
 //! # `return` statement
 //!  This type of statement divides into two logical steps:
 //!  * `return` sets the coroutine state to indicate termination.
@@ -111,6 +392,7 @@ mod stmt;
 mod test;
 
 use crate::attr::GentianAttr;
+use crate::stmt::CoContext;
 use generate_state_machines::Generator;
 use proc_macro::TokenStream;
 use syn::parse_macro_input;
@@ -132,12 +414,15 @@ fn transform_to_state_machine(mut input: syn::ItemFn) -> proc_macro2::TokenStrea
     }
     let state_name: String;
     let ret_val: String;
+    let co_ctx: CoContext;
     if let Some(attr) = &attrs {
         state_name = attr.get_state_name();
         ret_val = attr.get_ret_val();
+        co_ctx = attr.get_co_context();
     } else {
         state_name = "self.state".to_string();
         ret_val = String::new();
+        co_ctx = CoContext::default();
     }
     input.attrs.clear();
     if ret_val.is_empty() {
@@ -152,6 +437,7 @@ fn transform_to_state_machine(mut input: syn::ItemFn) -> proc_macro2::TokenStrea
         );
     }
     let mut generator = Generator::new();
+    generator.set_co_context(co_ctx);
     let expanded = generator.gen_state_machines_tokenstream(input, &state_name, &ret_val);
     return expanded;
 }
@@ -1,6 +1,10 @@
-use quote::ToTokens;
+use quote::{format_ident, ToTokens};
 #[allow(unused_imports)]
-use syn::{Block, Expr, ExprBlock, parse_quote};
+use syn::{Block, Expr, ExprBlock, parse_quote, Token};
+use syn::parse::{Parse, ParseStream};
+use syn::punctuated::Punctuated;
+use syn::token::Comma;
+use syn::visit_mut::VisitMut;
 use syn::ItemFn;
 use syn::Stmt;
 
@@ -15,6 +19,74 @@ pub(crate) fn dummy_expr()->Expr{
         }
     })
 }
+
+/// Places from `gentian_attr` that the `co_*` primitives are lowered onto.
+#[derive(Clone, Default)]
+pub(crate) struct CoContext {
+    pub(crate) emit_buf: Option<Expr>,
+    pub(crate) emit_offset: Option<Expr>,
+    pub(crate) resume_arg: Option<Expr>,
+    pub(crate) join_slots: Option<Expr>,
+    pub(crate) future_slot: Option<Expr>,
+    pub(crate) cx: Option<Expr>,
+    pub(crate) pending: Option<Expr>,
+    pub(crate) io_offset: Option<Expr>,
+    /// Loop iterations allowed per call before the machine yields to the executor.
+    pub(crate) budget: Option<Expr>,
+    pub(crate) ticks: Option<Expr>,
+    /// Places that `checkpoint` saves with the state, one place or a tuple of them.
+    pub(crate) storage: Option<Expr>,
+    /// Returned instead of panicking when a poisoned machine is called again.
+    pub(crate) poisoned: Option<Expr>,
+    /// Called with the old and the new state on every transition, a `Self::method` or a function.
+    pub(crate) on_transition: Option<Expr>,
+    /// The type of the state, for the constants of named states; `usize` by default.
+    pub(crate) state_type: Option<syn::Type>,
+    /// `co_await` goes through `gentian_core::Awaitable` instead of matching `Poll`.
+    pub(crate) awaitable: bool,
+    /// Generate `<fn>_reset`, which runs the active `co_defer!` blocks and restarts the machine.
+    pub(crate) reset: bool,
+    /// Keep a sentinel in the state while user code runs, see `Generator::poison_on_panic`.
+    pub(crate) poison_on_panic: bool,
+    /// Generate `<fn>_save` and `<fn>_load` for the state and `storage` through serde.
+    pub(crate) checkpoint: bool,
+    /// Number states by `#[state("label")]` labels or a hash of their statements.
+    pub(crate) stable_states: bool,
+    /// Generate `<fn>_state_info`, which tells where the machine is parked.
+    pub(crate) state_info: bool,
+    /// Log every call and transition through the `tracing` crate.
+    pub(crate) tracing: bool,
+    /// The state place itself, filled in by the generator.
+    pub(crate) state: Option<Expr>,
+}
+
+impl CoContext {
+    /// The value returned while suspended, `Poll::Pending` unless configured.
+    pub(crate) fn pending(&self) -> Expr {
+        match &self.pending {
+            Some(pending) => pending.clone(),
+            None => parse_quote! {::core::task::Poll::Pending},
+        }
+    }
+
+    /// The places listed in `storage`.
+    pub(crate) fn storage_places(&self) -> Vec<Expr> {
+        match &self.storage {
+            Some(Expr::Tuple(t)) => t.elems.iter().cloned().collect(),
+            Some(Expr::Paren(p)) => vec![(*p.expr).clone()],
+            Some(e) => vec![e.clone()],
+            None => vec![],
+        }
+    }
+}
+
+/// The place a primitive needs from `gentian_attr`, or an error at the statement using it.
+fn required_place<'a>(place: &'a Option<Expr>, at: impl ToTokens, msg: &str) -> syn::Result<&'a Expr> {
+    place
+        .as_ref()
+        .ok_or_else(|| syn::Error::new_spanned(at, format!("{} in `gentian_attr`", msg)))
+}
+
 pub(crate) fn nop_stmt() -> Stmt {
     let nop: ItemFn = parse_quote! {fn nop(){nop}};
     return nop.block.stmts[0].clone();
@@ -24,6 +96,25 @@ pub(crate) fn else_stmt() -> Stmt {
     let nop: ItemFn = parse_quote! {fn nop(){else_stmt}};
     return nop.block.stmts[0].clone();
 }
+pub(crate) fn resume_stmt() -> Stmt {
+    let nop: ItemFn = parse_quote! {fn nop(){resume_stmt}};
+    nop.block.stmts[0].clone()
+}
+pub(crate) fn jump_stmt() -> Stmt {
+    let nop: ItemFn = parse_quote! {fn nop(){jump_stmt}};
+    nop.block.stmts[0].clone()
+}
+
+/// Placeholder for the `co_defer!` blocks active at the node, run where the machine finishes.
+pub(crate) fn run_defers_stmt() -> Stmt {
+    parse_quote! {gentian_run_defers_;}
+}
+
+/// Placeholder for the state of CFG node `idx`, replaced once states are projected.
+pub(crate) fn state_of_node(idx: u32) -> syn::Ident {
+    format_ident!("gentian_state_of_node_{}_", idx)
+}
+
 pub(crate) fn final_stmt() -> Stmt {
     let nop: ItemFn = parse_quote! {fn nop(){final_stmt}};
     return nop.block.stmts[0].clone();
@@ -73,15 +164,43 @@ fn is_assign(e:&syn::Expr)->bool{
 }
 
 #[cfg(feature = "co_await")]
-fn transform_co_await_expr(origin_expr:&syn::Expr)->syn::Expr{
+fn ready_value_expr(poll: &impl ToTokens, ctx: &CoContext) -> syn::Expr {
+    let pending = ctx.pending();
+    if ctx.awaitable {
+        return parse_quote! {
+            match ::gentian_core::Awaitable::into_ready(#poll) {
+                ::core::option::Option::Some(ready_) => ready_,
+                ::core::option::Option::None => return #pending,
+            }
+        };
+    }
+    parse_quote! {
+        match #poll {
+            ::core::task::Poll::Ready(ready_) => ready_,
+            ::core::task::Poll::Pending => return #pending,
+        }
+    }
+}
+
+#[cfg(feature = "co_await")]
+fn transform_co_await_expr(origin_expr:&syn::Expr, ctx: &CoContext)->syn::Expr{
     if !is_assign(origin_expr){
         if let Expr::Call(e)=origin_expr{
             let args=&e.args;
+            let pending = ctx.pending();
+            let (tmp, is_pending): (Expr, Expr) = if ctx.awaitable {
+                (
+                    parse_quote! {::gentian_core::Awaitable::into_ready(#args)},
+                    parse_quote! {tmp_.is_none()},
+                )
+            } else {
+                (parse_quote! {#args}, parse_quote! {::core::task::Poll::is_pending(&tmp_)})
+            };
             let e:Expr=parse_quote!{
                 loop{
-                    let tmp_=#args;
-                    if tmp_.is_pending(){
-                        co_yield(Poll::Pending);
+                    let tmp_=#tmp;
+                    if #is_pending{
+                        co_yield(#pending);
                         continue;
                     }
                     break;
@@ -99,16 +218,9 @@ fn transform_co_await_expr(origin_expr:&syn::Expr)->syn::Expr{
             } else {
                 let left_expr=&*e.left;
                 if let Expr::Call(e)=&*e.right{
-                    let args=&e.args;
+                    let ready = ready_value_expr(&e.args, ctx);
                     let e:Expr=parse_quote!{
-                        loop{
-                            #left_expr=#args;
-                            if #left_expr.is_pending(){
-                                co_yield(Poll::Pending);
-                                continue;
-                            }
-                            break;
-                        }
+                        #left_expr = #ready
                     };
                     return e;
                 }
@@ -120,14 +232,24 @@ fn transform_co_await_expr(origin_expr:&syn::Expr)->syn::Expr{
 }
 
 #[cfg(feature = "co_await")]
-pub(crate) fn transform_co_await_stmt(stmt:&syn::Stmt)->syn::Stmt{
+pub(crate) fn transform_co_await_stmt(stmt:&syn::Stmt, ctx: &CoContext)->syn::Stmt{
     match stmt{
+        Stmt::Local(local) => {
+            let mut local = local.clone();
+            if let Some((_, e)) = &mut local.init {
+                if let Expr::Call(call) = &**e {
+                    let ready = ready_value_expr(&call.args, ctx);
+                    **e = ready;
+                }
+            }
+            return Stmt::Local(local);
+        }
         Stmt::Expr(e) => {
-            let new_expr=transform_co_await_expr(e);
+            let new_expr=transform_co_await_expr(e, ctx);
             return Stmt::Expr(new_expr);
         }
         Stmt::Semi(e, _) => {
-            let new_expr=transform_co_await_expr(e);
+            let new_expr=transform_co_await_expr(e, ctx);
             return Stmt::Semi(new_expr, Default::default());
             
         }
@@ -136,9 +258,24 @@ pub(crate) fn transform_co_await_stmt(stmt:&syn::Stmt)->syn::Stmt{
     return stmt.clone();
 }
 
+/// `let x = co_await(p);` and `x = co_await(p);` bind the value inside `Poll::Ready`.
+/// They poll from a resume point of their own and return `Poll::Pending` without leaving it.
+#[cfg(feature = "co_await")]
+pub(crate) fn is_co_await_binding_stmt(stmt: &syn::Stmt) -> bool {
+    match stmt {
+        Stmt::Local(_) => true,
+        Stmt::Expr(e) | Stmt::Semi(e, _) => is_assign(e),
+        _ => false,
+    }
+}
+
 #[cfg(feature = "co_await")]
 pub(crate) fn is_co_await_stmt(stmt:&syn::Stmt)->bool{
     return match stmt {
+        Stmt::Local(local) => match &local.init {
+            Some((_, e)) => is_co_call(e, "co_await"),
+            None => false,
+        },
         Stmt::Expr(e)|Stmt::Semi(e, _) => {
             is_co_await(e)
         }
@@ -146,6 +283,796 @@ pub(crate) fn is_co_await_stmt(stmt:&syn::Stmt)->bool{
     }
 }
 
+fn is_co_call(expr: &syn::Expr, name: &str) -> bool {
+    if let Expr::Call(e) = expr {
+        if let Expr::Path(path) = e.func.as_ref() {
+            return e.attrs.is_empty() && get_expr_path_name(path) == name;
+        }
+    }
+    false
+}
+
+/// The expression a `let` is initialized with, the right side of an assignment or compound
+/// assignment, or the expression of an expression statement.
+fn root_expr(stmt: &syn::Stmt) -> Option<&Expr> {
+    match stmt {
+        Stmt::Local(local) => local.init.as_ref().map(|(_, e)| &**e),
+        Stmt::Expr(Expr::Assign(e)) | Stmt::Semi(Expr::Assign(e), _) => Some(&e.right),
+        Stmt::Expr(Expr::AssignOp(e)) | Stmt::Semi(Expr::AssignOp(e), _) => Some(&e.right),
+        Stmt::Expr(e) | Stmt::Semi(e, _) => Some(e),
+        _ => None,
+    }
+}
+
+fn root_expr_mut(stmt: &mut syn::Stmt) -> Option<&mut Expr> {
+    match stmt {
+        Stmt::Local(local) => local.init.as_mut().map(|(_, e)| &mut **e),
+        Stmt::Expr(Expr::Assign(e)) | Stmt::Semi(Expr::Assign(e), _) => Some(&mut e.right),
+        Stmt::Expr(Expr::AssignOp(e)) | Stmt::Semi(Expr::AssignOp(e), _) => Some(&mut e.right),
+        Stmt::Expr(e) | Stmt::Semi(e, _) => Some(e),
+        _ => None,
+    }
+}
+
+/// Matches `name(..)` used as the root expression of a statement, see `root_expr`.
+pub(crate) fn is_co_call_stmt(stmt: &syn::Stmt, name: &str) -> bool {
+    match root_expr(stmt) {
+        Some(e) => is_co_call(e, name),
+        None => false,
+    }
+}
+
+/// Replaces the call matched by `is_co_call_stmt` with `f(args)`.
+pub(crate) fn map_co_call_stmt(
+    stmt: &syn::Stmt,
+    f: impl Fn(&Punctuated<Expr, Comma>) -> syn::Result<Expr>,
+) -> syn::Result<syn::Stmt> {
+    let mut stmt = stmt.clone();
+    if let Some(root) = root_expr_mut(&mut stmt) {
+        if let Expr::Call(e) = root {
+            let new_expr = f(&e.args)?;
+            *root = new_expr;
+        }
+    }
+    Ok(match stmt {
+        Stmt::Expr(e) => Stmt::Semi(e, semi_token()),
+        _ => stmt,
+    })
+}
+
+/// Matches `name(..)` used as a statement on its own.
+pub(crate) fn is_bare_co_call_stmt(stmt: &syn::Stmt, name: &str) -> bool {
+    match stmt {
+        Stmt::Expr(e) | Stmt::Semi(e, _) => is_co_call(e, name),
+        _ => false,
+    }
+}
+
+/// `co_wait_until(cond)` returns the pending value from its own state until `cond` holds.
+pub(crate) fn transform_co_wait_until_stmt(
+    stmt: &syn::Stmt,
+    ctx: &CoContext,
+) -> syn::Result<syn::Stmt> {
+    let pending = ctx.pending();
+    map_co_call_stmt(stmt, |args| {
+        if args.len() != 1 {
+            return Err(syn::Error::new_spanned(
+                stmt,
+                "`co_wait_until` takes exactly one argument",
+            ));
+        }
+        let cond = &args[0];
+        Ok(parse_quote! {
+            if !(#cond) {
+                return #pending;
+            }
+        })
+    })
+}
+
+/// `co_wait_ticks(n)` stores `n` in `ticks` before its resume point, and the returned check
+/// counts it down by one on every call, returning the pending value until it reaches zero.
+pub(crate) fn transform_co_wait_ticks_stmt(
+    stmt: &syn::Stmt,
+    ctx: &CoContext,
+) -> syn::Result<(syn::Stmt, syn::Stmt)> {
+    let ticks = required_place(&ctx.ticks, stmt, "`co_wait_ticks` requires `ticks`")?;
+    let pending = ctx.pending();
+    let store_stmt = map_co_call_stmt(stmt, |args| {
+        if args.len() != 1 {
+            return Err(syn::Error::new_spanned(
+                stmt,
+                "`co_wait_ticks` takes exactly one argument",
+            ));
+        }
+        let n = &args[0];
+        Ok(parse_quote! {#ticks = #n})
+    })?;
+    let check_stmt = parse_quote! {
+        if #ticks > 0 {
+            #ticks -= 1;
+            return #pending;
+        }
+    };
+    Ok((store_stmt, check_stmt))
+}
+
+/// `co_try_await(p)` awaits a `Poll<Result<T, E>>`, binds `T` and finishes the machine
+/// returning `Poll::Ready(Err(e.into()))` on error.
+pub(crate) fn transform_co_try_await_stmt(
+    stmt: &syn::Stmt,
+    ctx: &CoContext,
+    final_idx: u32,
+) -> syn::Result<syn::Stmt> {
+    let state = ctx.state.as_ref().unwrap();
+    let finished_state = state_of_node(final_idx);
+    let run_defers = run_defers_stmt();
+    let pending = ctx.pending();
+    map_co_call_stmt(stmt, |poll| {
+        Ok(parse_quote! {
+            match #poll {
+                ::core::task::Poll::Ready(::core::result::Result::Ok(ready_)) => ready_,
+                ::core::task::Poll::Ready(::core::result::Result::Err(err_)) => {
+                    #state = #finished_state;
+                    #run_defers
+                    return ::core::task::Poll::Ready(::core::result::Result::Err(err_.into()));
+                }
+                ::core::task::Poll::Pending => return #pending,
+            }
+        })
+    })
+}
+
+/// `co_await_io(r)` retries a non-blocking `io::Result<T>` on resume while
+/// `gentian_core::Awaitable` reports it pending, binds `T`, and finishes the machine returning
+/// `Poll::Ready(Err(e.into()))` on any other error.
+pub(crate) fn transform_co_await_io_stmt(
+    stmt: &syn::Stmt,
+    ctx: &CoContext,
+    final_idx: u32,
+) -> syn::Result<syn::Stmt> {
+    let state = ctx.state.as_ref().unwrap();
+    let finished_state = state_of_node(final_idx);
+    let run_defers = run_defers_stmt();
+    let pending = ctx.pending();
+    map_co_call_stmt(stmt, |res| {
+        Ok(parse_quote! {
+            match ::gentian_core::Awaitable::into_ready(#res) {
+                ::core::option::Option::Some(::core::result::Result::Ok(ready_)) => ready_,
+                ::core::option::Option::Some(::core::result::Result::Err(err_)) => {
+                    #state = #finished_state;
+                    #run_defers
+                    return ::core::task::Poll::Ready(::core::result::Result::Err(err_.into()));
+                }
+                ::core::option::Option::None => return #pending,
+            }
+        })
+    })
+}
+
+pub(crate) fn is_co_io_all_stmt(stmt: &syn::Stmt) -> bool {
+    match stmt {
+        Stmt::Expr(e) | Stmt::Semi(e, _) => {
+            is_co_call(e, "co_read_exact") || is_co_call(e, "co_write_all")
+        }
+        _ => false,
+    }
+}
+
+/// `co_read_exact(reader, cx, &mut buf)` and `co_write_all(writer, cx, &buf)` poll
+/// `poll_read`/`poll_write` until the whole buffer is transferred, keeping the progress in
+/// `io_offset`. A zero sized transfer or an error finishes the machine returning
+/// `Poll::Ready(Err(e.into()))`.
+pub(crate) fn transform_co_io_all_stmt(
+    stmt: &syn::Stmt,
+    ctx: &CoContext,
+    final_idx: u32,
+) -> syn::Result<syn::Stmt> {
+    let (name, args) = match stmt {
+        Stmt::Expr(Expr::Call(e)) | Stmt::Semi(Expr::Call(e), _) if e.args.len() == 3 => {
+            match e.func.as_ref() {
+                Expr::Path(path) => (get_expr_path_name(path), &e.args),
+                _ => unreachable!(),
+            }
+        }
+        _ => {
+            return Err(syn::Error::new_spanned(
+                stmt,
+                "`co_read_exact` and `co_write_all` take exactly three arguments",
+            ))
+        }
+    };
+    let offset = required_place(
+        &ctx.io_offset,
+        stmt,
+        "`co_read_exact` and `co_write_all` require `io_offset`",
+    )?;
+    let state = ctx.state.as_ref().unwrap();
+    let finished_state = state_of_node(final_idx);
+    let run_defers = run_defers_stmt();
+    let pending = ctx.pending();
+    let (io, cx, buf) = (&args[0], &args[1], &args[2]);
+    let (poll, zero_kind): (Expr, syn::Ident) = if name == "co_read_exact" {
+        (
+            parse_quote! {::core::pin::Pin::new(&mut #io).poll_read(#cx, &mut (#buf)[#offset..])},
+            format_ident!("UnexpectedEof"),
+        )
+    } else {
+        (
+            parse_quote! {::core::pin::Pin::new(&mut #io).poll_write(#cx, &(#buf)[#offset..])},
+            format_ident!("WriteZero"),
+        )
+    };
+    Ok(parse_quote! {{
+        while #offset < (#buf).len() {
+            let err_ = match #poll {
+                ::core::task::Poll::Ready(::core::result::Result::Ok(0)) => {
+                    ::std::io::Error::from(::std::io::ErrorKind::#zero_kind)
+                }
+                ::core::task::Poll::Ready(::core::result::Result::Ok(n_)) => {
+                    #offset += n_;
+                    continue;
+                }
+                ::core::task::Poll::Ready(::core::result::Result::Err(err_)) => err_,
+                ::core::task::Poll::Pending => return #pending,
+            };
+            #offset = 0;
+            #state = #finished_state;
+            #run_defers
+            return ::core::task::Poll::Ready(::core::result::Result::Err(err_.into()));
+        }
+        #offset = 0;
+    }})
+}
+
+/// Counts a loop back edge in its `counter`, which starts from zero on each call. Once the edge
+/// is taken more than `budget` times the waker is notified and the machine suspends at the loop
+/// head `head_idx`.
+pub(crate) fn budget_check_stmt(
+    ctx: &CoContext,
+    head_idx: u32,
+    counter: &syn::Ident,
+) -> syn::Result<syn::Stmt> {
+    let budget = ctx.budget.as_ref().unwrap();
+    let cx = required_place(&ctx.cx, budget, "`budget` requires `cx`")?;
+    let state = ctx.state.as_ref().unwrap();
+    let head_state = state_of_node(head_idx);
+    let pending = ctx.pending();
+    Ok(parse_quote! {{
+        #counter += 1;
+        if #counter > #budget {
+            #cx.waker().wake_by_ref();
+            #state = #head_state;
+            return #pending;
+        }
+    }})
+}
+
+pub(crate) fn is_co_await_next_cond(cond: &syn::Expr) -> bool {
+    match cond {
+        Expr::Let(e) => is_co_call(&e.expr, "co_await_next"),
+        _ => false,
+    }
+}
+
+/// The condition of `while let Some(item) = co_await_next(p)` becomes a `let` of the item,
+/// which returns `Poll::Pending` while `p` is pending and leaves the loop on `Ready(None)`.
+pub(crate) fn transform_co_await_next_cond(
+    cond: &syn::Expr,
+    ctx: &CoContext,
+    end_idx: u32,
+) -> syn::Result<syn::Stmt> {
+    let (pat, poll) = match cond {
+        Expr::Let(e) => match (&e.pat, &*e.expr) {
+            (syn::Pat::TupleStruct(p), Expr::Call(call))
+                if p.path.is_ident("Some") && p.pat.elems.len() == 1 =>
+            {
+                (&p.pat.elems[0], &call.args)
+            }
+            _ => {
+                return Err(syn::Error::new_spanned(
+                    &e.pat,
+                    "`co_await_next` must be matched against `Some(..)`",
+                ))
+            }
+        },
+        _ => unreachable!(),
+    };
+    let state = ctx.state.as_ref().unwrap();
+    let end_state = state_of_node(end_idx);
+    let pending = ctx.pending();
+    Ok(parse_quote! {
+        let #pat = match #poll {
+            ::core::task::Poll::Ready(::core::option::Option::Some(ready_)) => ready_,
+            ::core::task::Poll::Ready(::core::option::Option::None) => {
+                #state = #end_state;
+                continue 'genloop;
+            }
+            ::core::task::Poll::Pending => return #pending,
+        };
+    })
+}
+
+/// `co_join(a, b, ..)` polls every unfinished expression, parks ready values in `join_slots`
+/// and returns `Poll::Pending` until all of them are done. Then it takes the tuple of values.
+pub(crate) fn transform_co_join_stmt(stmt: &syn::Stmt, ctx: &CoContext) -> syn::Result<syn::Stmt> {
+    let slots = required_place(&ctx.join_slots, stmt, "`co_join` requires `join_slots`")?;
+    let pending = ctx.pending();
+    map_co_call_stmt(stmt, |polls| {
+        let idx: Vec<syn::Index> = (0..polls.len()).map(syn::Index::from).collect();
+        let polls = polls.iter();
+        Ok(parse_quote! {
+            {
+                #(
+                    if #slots.#idx.is_none() {
+                        if let ::core::task::Poll::Ready(ready_) = #polls {
+                            #slots.#idx = ::core::option::Option::Some(ready_);
+                        }
+                    }
+                )*
+                if false #(|| #slots.#idx.is_none())* {
+                    return #pending;
+                }
+                (#(#slots.#idx.take().unwrap(),)*)
+            }
+        })
+    })
+}
+
+pub(crate) fn is_co_await_future_stmt(stmt: &syn::Stmt) -> bool {
+    match root_expr(stmt) {
+        Some(Expr::Await(_)) => true,
+        Some(e) => is_co_call(e, "co_await_future"),
+        None => false,
+    }
+}
+
+/// `co_await_future(fut)` or `fut.await` stores the future pinned in its place of `future_slot`,
+/// then polls it with `cx` from a resume point until it is ready. Returns the statement storing
+/// the future and the statement polling it.
+pub(crate) fn transform_co_await_future_stmt(
+    stmt: &syn::Stmt,
+    ctx: &CoContext,
+) -> syn::Result<(syn::Stmt, syn::Stmt)> {
+    let slot = required_place(&ctx.future_slot, stmt, "awaiting a future requires `future_slot`")?;
+    let cx: Expr = match &ctx.cx {
+        Some(cx) => cx.clone(),
+        None => parse_quote! {cx},
+    };
+    let pending = ctx.pending();
+    let mut poll_stmt = stmt.clone();
+    let root = root_expr_mut(&mut poll_stmt).unwrap();
+    let fut = match root {
+        Expr::Await(e) => (*e.base).clone(),
+        Expr::Call(e) if e.args.len() == 1 => e.args[0].clone(),
+        _ => {
+            return Err(syn::Error::new_spanned(
+                stmt,
+                "`co_await_future` takes exactly one argument",
+            ))
+        }
+    };
+    *root = parse_quote! {
+        match ::core::future::Future::poll(#slot.as_mut().unwrap().as_mut(), #cx) {
+            ::core::task::Poll::Ready(ready_) => {
+                #slot = ::core::option::Option::None;
+                ready_
+            }
+            ::core::task::Poll::Pending => return #pending,
+        }
+    };
+    if let Stmt::Expr(e) = poll_stmt {
+        poll_stmt = Stmt::Semi(e, semi_token());
+    }
+    // `alloc` is linked by std crates too, so one path pins in both std and `no_std` crates.
+    let store_stmt = parse_quote! {
+        #slot = ::core::option::Option::Some({
+            extern crate alloc as gentian_alloc_;
+            gentian_alloc_::boxed::Box::pin(#fut)
+        });
+    };
+    Ok((store_stmt, poll_stmt))
+}
+
+/// One `pat = poll => { .. }` arm of `co_select!`.
+pub(crate) struct SelectArm {
+    pub(crate) pat: syn::Pat,
+    pub(crate) poll: Expr,
+    pub(crate) body: Block,
+}
+
+impl Parse for SelectArm {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let pat = input.parse()?;
+        input.parse::<Token![=]>()?;
+        let poll = input.parse()?;
+        input.parse::<Token![=>]>()?;
+        let body = input.parse()?;
+        input.parse::<Option<Token![,]>>()?;
+        Ok(SelectArm { pat, poll, body })
+    }
+}
+
+fn expr_attrs_mut(expr: &mut Expr) -> Option<&mut Vec<syn::Attribute>> {
+    match expr {
+        Expr::Assign(e) => Some(&mut e.attrs),
+        Expr::AssignOp(e) => Some(&mut e.attrs),
+        Expr::Await(e) => Some(&mut e.attrs),
+        Expr::Binary(e) => Some(&mut e.attrs),
+        Expr::Block(e) => Some(&mut e.attrs),
+        Expr::Call(e) => Some(&mut e.attrs),
+        Expr::Cast(e) => Some(&mut e.attrs),
+        Expr::Field(e) => Some(&mut e.attrs),
+        Expr::If(e) => Some(&mut e.attrs),
+        Expr::Index(e) => Some(&mut e.attrs),
+        Expr::Loop(e) => Some(&mut e.attrs),
+        Expr::Macro(e) => Some(&mut e.attrs),
+        Expr::MethodCall(e) => Some(&mut e.attrs),
+        Expr::Path(e) => Some(&mut e.attrs),
+        Expr::Return(e) => Some(&mut e.attrs),
+        Expr::Try(e) => Some(&mut e.attrs),
+        Expr::While(e) => Some(&mut e.attrs),
+        Expr::Yield(e) => Some(&mut e.attrs),
+        _ => None,
+    }
+}
+
+/// Removes the attribute `#[name(..)]` from the expression. Depending on the syn version, the
+/// outer attribute of `#[a] x = y` belongs to the assignment or to its leftmost operand `x`, so
+/// the leftmost operands are searched as well.
+fn take_expr_attr(expr: &mut Expr, name: &str) -> Option<syn::Attribute> {
+    if let Some(attrs) = expr_attrs_mut(expr) {
+        if let Some(pos) = attrs.iter().position(|a| a.path.is_ident(name)) {
+            return Some(attrs.remove(pos));
+        }
+    }
+    let leftmost = match expr {
+        Expr::Assign(e) => &mut e.left,
+        Expr::AssignOp(e) => &mut e.left,
+        Expr::Await(e) => &mut e.base,
+        Expr::Binary(e) => &mut e.left,
+        Expr::Call(e) => &mut e.func,
+        Expr::Cast(e) => &mut e.expr,
+        Expr::Field(e) => &mut e.base,
+        Expr::Index(e) => &mut e.expr,
+        Expr::MethodCall(e) => &mut e.receiver,
+        Expr::Try(e) => &mut e.expr,
+        _ => return None,
+    };
+    take_expr_attr(leftmost, name)
+}
+
+/// Removes the attribute `#[name(..)]` from the statement, returning it and the statement
+/// without it.
+fn take_stmt_attr(stmt: &syn::Stmt, name: &str) -> Option<(syn::Attribute, syn::Stmt)> {
+    let mut stmt = stmt.clone();
+    let attrs = match &mut stmt {
+        Stmt::Local(local) => &mut local.attrs,
+        Stmt::Item(syn::Item::Macro(m)) => &mut m.attrs,
+        Stmt::Expr(e) | Stmt::Semi(e, _) => {
+            let attr = take_expr_attr(e, name)?;
+            return Some((attr, stmt));
+        }
+        _ => return None,
+    };
+    let pos = attrs.iter().position(|a| a.path.is_ident(name))?;
+    let attr = attrs.remove(pos);
+    Some((attr, stmt))
+}
+
+/// Removes a `#[state("label")]` attribute from the statement, returning the label and the
+/// statement without it.
+pub(crate) fn take_state_label(stmt: &syn::Stmt) -> syn::Result<Option<(syn::LitStr, syn::Stmt)>> {
+    let (attr, stmt) = match take_stmt_attr(stmt, "state") {
+        Some(taken) => taken,
+        None => return Ok(None),
+    };
+    let label = attr.parse_args::<syn::LitStr>().map_err(|_| {
+        syn::Error::new_spanned(
+            &attr,
+            "`#[state(..)]` takes a string literal, as in `#[state(\"handshake_done\")]`",
+        )
+    })?;
+    Ok(Some((label, stmt)))
+}
+
+/// Removes a `#[gentian_state(name = "..")]` attribute from the statement, returning the name
+/// and the statement without it.
+pub(crate) fn take_state_name(stmt: &syn::Stmt) -> syn::Result<Option<(syn::LitStr, syn::Stmt)>> {
+    let (attr, stmt) = match take_stmt_attr(stmt, "gentian_state") {
+        Some(taken) => taken,
+        None => return Ok(None),
+    };
+    let name = attr
+        .parse_args_with(|input: ParseStream| {
+            let key: syn::Ident = input.parse()?;
+            if key != "name" {
+                return Err(syn::Error::new(key.span(), "expected `name`"));
+            }
+            input.parse::<Token![=]>()?;
+            input.parse::<syn::LitStr>()
+        })
+        .map_err(|_| {
+            syn::Error::new_spanned(
+                &attr,
+                "`#[gentian_state(..)]` looks like `#[gentian_state(name = \"await_header\")]`",
+            )
+        })?;
+    Ok(Some((name, stmt)))
+}
+
+/// Matches a `name! { .. }` statement.
+fn get_co_macro<'a>(stmt: &'a syn::Stmt, name: &str) -> Option<&'a syn::Macro> {
+    let mac = match stmt {
+        Stmt::Item(syn::Item::Macro(m)) => &m.mac,
+        Stmt::Expr(Expr::Macro(m)) | Stmt::Semi(Expr::Macro(m), _) => &m.mac,
+        _ => return None,
+    };
+    if mac.path.is_ident(name) {
+        Some(mac)
+    } else {
+        None
+    }
+}
+
+/// Returns the body of a `co_defer! { .. }` statement.
+pub(crate) fn get_co_defer_block(stmt: &syn::Stmt) -> syn::Result<Option<syn::Block>> {
+    let mac = match get_co_macro(stmt, "co_defer") {
+        Some(mac) => mac,
+        None => return Ok(None),
+    };
+    let stmts = mac
+        .parse_body_with(Block::parse_within)
+        .map_err(|err| syn::Error::new(err.span(), "`co_defer!` takes a block of statements"))?;
+    Ok(Some(Block {
+        brace_token: Default::default(),
+        stmts,
+    }))
+}
+
+/// Lowers every `?` of the statement into a `match` that finishes the machine and runs the
+/// active `co_defer!` blocks before the error is returned, by a `?` of its own so that it is
+/// converted as usual. The ones of closures, async blocks and items return from those rather
+/// than from the generator and are left alone. Returns `None` when there is no `?`.
+pub(crate) fn transform_try_exprs(
+    stmt: &syn::Stmt,
+    ctx: &CoContext,
+    final_idx: u32,
+) -> Option<syn::Stmt> {
+    struct LowerTry {
+        state: Expr,
+        finished_state: syn::Ident,
+        found: bool,
+    }
+    impl VisitMut for LowerTry {
+        fn visit_expr_mut(&mut self, expr: &mut Expr) {
+            syn::visit_mut::visit_expr_mut(self, expr);
+            if let Expr::Try(e) = expr {
+                self.found = true;
+                let (state, finished_state) = (&self.state, &self.finished_state);
+                let run_defers = run_defers_stmt();
+                let operand = &e.expr;
+                let mut forward: syn::ExprTry = parse_quote! {
+                    ::core::result::Result::<::core::convert::Infallible, _>::Err(err_)?
+                };
+                forward.question_token = e.question_token;
+                *expr = parse_quote! {
+                    match #operand {
+                        ::core::result::Result::Ok(ok_) => ok_,
+                        ::core::result::Result::Err(err_) => {
+                            #state = #finished_state;
+                            #run_defers
+                            match #forward {}
+                        }
+                    }
+                };
+            }
+        }
+        fn visit_expr_closure_mut(&mut self, _: &mut syn::ExprClosure) {}
+        fn visit_expr_async_mut(&mut self, _: &mut syn::ExprAsync) {}
+        fn visit_item_mut(&mut self, _: &mut syn::Item) {}
+    }
+    let mut lower = LowerTry {
+        state: ctx.state.clone().unwrap(),
+        finished_state: state_of_node(final_idx),
+        found: false,
+    };
+    let mut stmt = stmt.clone();
+    lower.visit_stmt_mut(&mut stmt);
+    if lower.found {
+        Some(stmt)
+    } else {
+        None
+    }
+}
+
+/// Returns the arms of a `co_select! { .. }` statement.
+pub(crate) fn get_co_select_arms(stmt: &syn::Stmt) -> syn::Result<Option<Vec<SelectArm>>> {
+    let mac = match get_co_macro(stmt, "co_select") {
+        Some(mac) => mac,
+        None => return Ok(None),
+    };
+    let arms = mac
+        .parse_body_with(|input: ParseStream| {
+            let mut arms = Vec::new();
+            while !input.is_empty() {
+                arms.push(input.parse::<SelectArm>()?);
+            }
+            Ok(arms)
+        })
+        .map_err(|err| {
+            syn::Error::new(err.span(), "`co_select!` arms must look like `pat = poll => { .. }`")
+        })?;
+    Ok(Some(arms))
+}
+
+/// Polls the arm's expression and binds its pattern to the value inside `Poll::Ready`.
+/// When pending, the statement moves on to the arm at `next_idx`, or after the last arm
+/// returns `Poll::Pending` to poll every arm again from `first_idx` on the next call.
+pub(crate) fn transform_co_select_arm(
+    arm: &SelectArm,
+    ctx: &CoContext,
+    first_idx: u32,
+    next_idx: Option<u32>,
+) -> syn::Stmt {
+    let state = ctx.state.as_ref().unwrap();
+    let pat = &arm.pat;
+    let poll = &arm.poll;
+    let pending: Expr = match next_idx {
+        Some(idx) => {
+            let next_state = state_of_node(idx);
+            parse_quote! {
+                {
+                    #state = #next_state;
+                    continue 'genloop;
+                }
+            }
+        }
+        None => {
+            let first_state = state_of_node(first_idx);
+            let pending = ctx.pending();
+            parse_quote! {
+                {
+                    #state = #first_state;
+                    return #pending;
+                }
+            }
+        }
+    };
+    parse_quote! {
+        let #pat = match #poll {
+            ::core::task::Poll::Ready(ready_) => ready_,
+            ::core::task::Poll::Pending => #pending
+        };
+    }
+}
+
+pub(crate) fn is_co_emit_stmt(stmt: &syn::Stmt) -> bool {
+    match stmt {
+        Stmt::Expr(e) | Stmt::Semi(e, _) => is_co_call(e, "co_emit"),
+        _ => false,
+    }
+}
+
+/// `co_emit(bytes)` copies as much of `bytes` as fits into `emit_buf` and returns
+/// `Poll::Ready(Ok(n))`, resuming at `emit_offset` on the next call until all of `bytes`
+/// has been handed out.
+pub(crate) fn transform_co_emit_stmt(stmt: &syn::Stmt, ctx: &CoContext) -> syn::Result<syn::Stmt> {
+    let bytes = match stmt {
+        Stmt::Expr(Expr::Call(e)) | Stmt::Semi(Expr::Call(e), _) if e.args.len() == 1 => {
+            &e.args[0]
+        }
+        _ => {
+            return Err(syn::Error::new_spanned(
+                stmt,
+                "`co_emit` takes exactly one argument",
+            ))
+        }
+    };
+    let offset = required_place(&ctx.emit_offset, stmt, "`co_emit` requires `emit_offset`")?;
+    let buf: Expr = match &ctx.emit_buf {
+        Some(buf) => buf.clone(),
+        None => parse_quote! {buf},
+    };
+    Ok(parse_quote! {
+        {
+            while #offset < (#bytes).len() {
+                let n_ = {
+                    let src_ = &(#bytes)[#offset..];
+                    let n_ = ::core::cmp::min(src_.len(), #buf.len());
+                    #buf[..n_].copy_from_slice(&src_[..n_]);
+                    n_
+                };
+                #offset += n_;
+                co_yield(::core::task::Poll::Ready(::core::result::Result::Ok(n_)));
+            }
+            #offset = 0;
+        }
+    })
+}
+
+pub(crate) fn is_co_yield_binding_stmt(stmt: &syn::Stmt) -> bool {
+    match stmt {
+        Stmt::Local(local) => match &local.init {
+            Some((_, e)) => is_co_call(e, "co_yield"),
+            None => false,
+        },
+        Stmt::Expr(Expr::Assign(e)) | Stmt::Semi(Expr::Assign(e), _) => {
+            is_co_call(&e.right, "co_yield")
+        }
+        _ => false,
+    }
+}
+
+/// `let x = co_yield(v);` yields `v`, and on the next call binds `x` to the `resume_arg`
+/// parameter that call was made with.
+pub(crate) fn transform_co_yield_binding_stmt(
+    stmt: &syn::Stmt,
+    ctx: &CoContext,
+) -> syn::Result<syn::Stmt> {
+    required_place(
+        &ctx.resume_arg,
+        stmt,
+        "using the value of `co_yield` requires `resume_arg`",
+    )?;
+    let resume: Expr = parse_quote! {resume_arg_.take().unwrap()};
+    Ok(match stmt {
+        Stmt::Local(local) => {
+            let mut local = local.clone();
+            let (eq, yield_expr) = local.init.take().unwrap();
+            local.init = Some((eq, Box::new(resume)));
+            parse_quote! {
+                {
+                    #yield_expr;
+                    #local
+                }
+            }
+        }
+        Stmt::Expr(Expr::Assign(e)) | Stmt::Semi(Expr::Assign(e), _) => {
+            let left = &e.left;
+            let yield_expr = &e.right;
+            parse_quote! {
+                {
+                    #yield_expr;
+                    #left = #resume;
+                }
+            }
+        }
+        _ => stmt.clone(),
+    })
+}
+
+pub(crate) fn is_co_yield_from_stmt(stmt: &syn::Stmt) -> bool {
+    match stmt {
+        Stmt::Expr(e) | Stmt::Semi(e, _) => is_co_call(e, "co_yield_from"),
+        _ => false,
+    }
+}
+
+/// `co_yield_from(child)` resumes the child iterator once per call and yields its `Some`
+/// values as our own. It returns without leaving its state, so the delegation is re-entered
+/// until the child is exhausted.
+pub(crate) fn transform_co_yield_from_stmt(stmt: &syn::Stmt) -> syn::Result<syn::Stmt> {
+    let child = match stmt {
+        Stmt::Expr(Expr::Call(e)) | Stmt::Semi(Expr::Call(e), _) if e.args.len() == 1 => {
+            &e.args[0]
+        }
+        _ => {
+            return Err(syn::Error::new_spanned(
+                stmt,
+                "`co_yield_from` takes exactly one argument",
+            ))
+        }
+    };
+    Ok(parse_quote! {
+        {
+            let next_ = ::core::iter::Iterator::next(&mut #child);
+            if next_.is_some() {
+                return next_;
+            }
+        }
+    })
+}
 
 fn is_co_expr_path(path: &syn::ExprPath) -> bool {
     let name = get_expr_path_name(path);
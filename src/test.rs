@@ -146,7 +146,7 @@ fn test_is_co_yield_or_co_return() {
 #[cfg(feature = "co_await")]
 #[test]
 fn test_co_await(){
-    use crate::stmt::{is_co_await_stmt,transform_co_await_stmt};
+    use crate::stmt::{is_co_await_stmt,transform_co_await_stmt,CoContext};
     use quote::ToTokens;
     use syn::parse_quote;
     use syn::ItemFn;
@@ -157,7 +157,7 @@ fn test_co_await(){
     }
     let stmt = co_await_1();
     assert!(is_co_await_stmt(&stmt));
-    let new_stmt = transform_co_await_stmt(&stmt);
+    let new_stmt = transform_co_await_stmt(&stmt, &CoContext::default());
     println!("{}",new_stmt.to_token_stream().to_string());
     fn co_await_2() -> syn::Stmt {
         let nop: ItemFn = parse_quote! {fn nop(){co_await(wtf);}};
@@ -165,6 +165,391 @@ fn test_co_await(){
     }
     let stmt = co_await_2();
     assert!(is_co_await_stmt(&stmt));
-    let new_stmt = transform_co_await_stmt(&stmt);
+    let new_stmt = transform_co_await_stmt(&stmt, &CoContext::default());
     println!("{}",new_stmt.to_token_stream().to_string());
+    fn co_await_3() -> syn::Stmt {
+        let nop: ItemFn = parse_quote! {fn nop(){let (a, b) = co_await(wtf);}};
+        return nop.block.stmts[0].clone();
+    }
+    let stmt = co_await_3();
+    assert!(is_co_await_stmt(&stmt));
+    let new_stmt = transform_co_await_stmt(&stmt, &CoContext::default());
+    println!("{}",new_stmt.to_token_stream().to_string());
+}
+
+#[test]
+fn test_co_wait_until_state() {
+    use crate::generate_state_machines::Generator;
+    use syn::parse_quote;
+    use syn::ItemFn;
+    let f: ItemFn = parse_quote! {
+        fn poll_ready(&mut self) -> Poll<()> {
+            prepare();
+            co_wait_until(self.ready());
+            return Poll::Ready(());
+        }
+    };
+    let mut generator = Generator::new();
+    generator.gen_state_machines_tokenstream(f, "self.state", "Poll::Ready(())");
+    let cfg_state_graph = generator.get_cfg_state_graph();
+    let wait_nodes: Vec<&str> = cfg_state_graph
+        .lines()
+        .filter(|l| l.contains("ready ()"))
+        .collect();
+    assert_eq!(wait_nodes.len(), 1);
+    assert!(wait_nodes[0].contains(", wait_until (self . ready ()) \"]"));
+    assert!(!wait_nodes[0].contains("state:0,"));
+}
+
+#[cfg(test)]
+fn state_of_stmt(f: syn::ItemFn, ctx: crate::stmt::CoContext, stmt: &str) -> String {
+    use crate::generate_state_machines::Generator;
+    let mut generator = Generator::new();
+    generator.set_co_context(ctx);
+    generator.gen_state_machines_tokenstream(f, "self.state", "");
+    let cfg_state_graph = generator.get_cfg_state_graph();
+    let line = cfg_state_graph
+        .lines()
+        .find(|l| l.contains(stmt))
+        .unwrap();
+    let state = line.split("state:").nth(1).unwrap();
+    state.split(',').next().unwrap().to_string()
+}
+
+#[test]
+fn test_stable_states() {
+    use crate::stmt::CoContext;
+    use syn::parse_quote;
+    use syn::ItemFn;
+    let stable = || CoContext {
+        stable_states: true,
+        ..Default::default()
+    };
+    let f: ItemFn = parse_quote! {
+        fn next(&mut self) -> Option<u32> {
+            co_yield(Some(1));
+            #[state("handshake_done")]
+            co_yield(Some(2));
+            after_handshake();
+            co_yield(Some(3));
+            finish();
+        }
+    };
+    let edited: ItemFn = parse_quote! {
+        fn next(&mut self) -> Option<u32> {
+            setup();
+            co_yield(Some(0));
+            co_yield(Some(1));
+            #[state("handshake_done")]
+            co_yield(Some(2));
+            after_handshake();
+            co_yield(Some(3));
+            finish();
+        }
+    };
+    for stmt in ["after_handshake ()", "finish ()"] {
+        let before = state_of_stmt(f.clone(), stable(), stmt);
+        let after = state_of_stmt(edited.clone(), stable(), stmt);
+        assert_eq!(before, after);
+        let renumbered = state_of_stmt(edited.clone(), CoContext::default(), stmt);
+        assert_ne!(before, renumbered);
+    }
+}
+
+/// `#[state(..)]` on `x = y`, attached to the assignment or to `x` as different syn versions do.
+#[cfg(test)]
+fn attr_placements(attr: syn::Attribute) -> [syn::Stmt; 2] {
+    use syn::{parse_quote, Expr, Stmt};
+    let plain: Stmt = parse_quote! { self.out = co_yield(Some(2)); };
+    let mut on_assign = plain.clone();
+    let mut on_left = plain;
+    if let Stmt::Semi(Expr::Assign(assign), _) = &mut on_assign {
+        assign.attrs.push(attr.clone());
+    }
+    if let Stmt::Semi(Expr::Assign(assign), _) = &mut on_left {
+        if let Expr::Field(field) = &mut *assign.left {
+            field.attrs.push(attr);
+        }
+    }
+    [on_assign, on_left]
+}
+
+#[test]
+fn test_state_label_placements() {
+    use crate::stmt::take_state_label;
+    use syn::parse_quote;
+    let plain: syn::Stmt = parse_quote! { self.out = co_yield(Some(2)); };
+    for stmt in attr_placements(parse_quote!(#[state("handshake_done")])) {
+        assert_ne!(stmt, plain);
+        let (label, stmt) = take_state_label(&stmt).unwrap().unwrap();
+        assert_eq!(label.value(), "handshake_done");
+        assert_eq!(stmt, plain);
+    }
+    assert!(take_state_label(&plain).unwrap().is_none());
+}
+
+#[test]
+fn test_stable_states_collision() {
+    use crate::generate_state_machines::Generator;
+    use crate::stmt::CoContext;
+    use syn::parse_quote;
+    use syn::ItemFn;
+    let stable = || CoContext {
+        stable_states: true,
+        ..Default::default()
+    };
+    let f: ItemFn = parse_quote! {
+        fn next(&mut self) -> Option<u32> {
+            co_yield(None);
+            co_yield(None);
+            co_yield(None);
+        }
+    };
+    let mut generator = Generator::new();
+    generator.set_co_context(stable());
+    let code = generator
+        .gen_state_machines_tokenstream(f, "self.state", "")
+        .to_string();
+    assert!(code.contains("compile_error"));
+    assert!(code.contains("share the state id"));
+    assert!(code.contains("the other state starts here"));
+
+    let f: ItemFn = parse_quote! {
+        fn next(&mut self) -> Option<u32> {
+            co_yield(None);
+            #[state("first")]
+            f();
+            #[state("second")]
+            g();
+        }
+    };
+    let mut generator = Generator::new();
+    generator.set_co_context(stable());
+    let code = generator
+        .gen_state_machines_tokenstream(f, "self.state", "")
+        .to_string();
+    assert!(code.contains("compile_error"));
+    assert!(code.contains("label the same state"));
+    assert!(code.contains("first label here"));
+}
+
+#[test]
+fn test_named_states() {
+    use crate::generate_state_machines::Generator;
+    use syn::parse_quote;
+    use syn::ItemFn;
+    let f: ItemFn = parse_quote! {
+        fn poll_read(&mut self) -> Poll<u8> {
+            #[gentian_state(name = "await_header")]
+            self.len = co_await(self.header);
+            return Poll::Ready(self.len);
+        }
+    };
+    let mut generator = Generator::new();
+    let code = generator
+        .gen_state_machines_tokenstream(f, "self.state", "")
+        .to_string();
+    assert!(code.contains("const POLL_READ_AWAIT_HEADER : usize = 1"));
+    assert!(code.contains("# [doc = \" state `await_header`\"] 1 =>"));
+    assert!(generator
+        .get_cfg_state_graph()
+        .contains("state:1 (await_header), self . len = match"));
+
+    let f: ItemFn = parse_quote! {
+        fn poll_read(&mut self) -> Poll<u8> {
+            #[gentian_state(name = "await_header")]
+            self.len = co_await(self.header);
+            #[gentian_state(name = "await_header")]
+            self.len = co_await(self.body);
+            return Poll::Ready(self.len);
+        }
+    };
+    let code = Generator::new()
+        .gen_state_machines_tokenstream(f, "self.state", "")
+        .to_string();
+    assert!(code.contains("compile_error"));
+    assert!(code.contains("duplicate state name `await_header`"));
+    assert!(code.contains("first used here"));
+}
+
+#[test]
+fn test_state_name_placements() {
+    use crate::stmt::take_state_name;
+    use syn::parse_quote;
+    let plain: syn::Stmt = parse_quote! { self.out = co_yield(Some(2)); };
+    for stmt in attr_placements(parse_quote!(#[gentian_state(name = "await_header")])) {
+        let (name, stmt) = take_state_name(&stmt).unwrap().unwrap();
+        assert_eq!(name.value(), "await_header");
+        assert_eq!(stmt, plain);
+    }
+    let local: syn::Stmt = parse_quote! {
+        #[gentian_state(name = "await_header")]
+        let len = co_await(self.header);
+    };
+    let (name, _) = take_state_name(&local).unwrap().unwrap();
+    assert_eq!(name.value(), "await_header");
+}
+
+#[test]
+fn test_co_defer_lowers_try() {
+    use crate::generate_state_machines::Generator;
+    use syn::parse_quote;
+    use syn::ItemFn;
+    let f: ItemFn = parse_quote! {
+        fn poll_job(&mut self) -> Poll<io::Result<u32>> {
+            self.n = self.first?;
+            co_defer! {
+                self.log.push("close");
+            }
+            let parse = |s: &str| -> io::Result<u32> { Ok(s.parse::<u32>().map_err(other)?) };
+            co_yield(Poll::Pending);
+            let v = self.next.take().unwrap()?;
+            return Poll::Ready(Ok(v));
+        }
+    };
+    let code = Generator::new()
+        .gen_state_machines_tokenstream(f, "self.state", "Poll::Ready(Ok(0))")
+        .to_string();
+    assert!(!code.contains("compile_error"));
+    // the `?` before the `co_defer!` and the one of the closure are left alone.
+    assert!(code.contains("self . n = self . first ?"));
+    assert!(code.contains("map_err (other) ?"));
+    assert!(code.contains("match self . next . take () . unwrap ()"));
+    assert!(code.contains(
+        "{ self . log . push (\"close\") ; } \
+         match :: core :: result :: Result :: < :: core :: convert :: Infallible , _ > :: Err (err_) ? { }"
+    ));
+
+    let f: ItemFn = parse_quote! {
+        fn poll_job(&mut self) -> Poll<io::Result<u32>> {
+            self.n = self.first?;
+            co_defer! {
+                self.log.push("close");
+            }
+            let parse = |s: &str| -> io::Result<u32> { Ok(s.parse::<u32>().map_err(other)?) };
+            return Poll::Ready(Ok(self.n));
+        }
+    };
+    let code = Generator::new()
+        .gen_state_machines_tokenstream(f, "self.state", "Poll::Ready(Ok(0))")
+        .to_string();
+    assert!(!code.contains("compile_error"));
+}
+
+#[test]
+fn test_co_misuse_is_compile_error() {
+    use crate::generate_state_machines::Generator;
+    use syn::parse_quote;
+    use syn::ItemFn;
+    let f: ItemFn = parse_quote! {
+        fn poll_job(&mut self) -> Poll<u32> {
+            co_wait_until(self.ready, self.other);
+            return Poll::Ready(1);
+        }
+    };
+    let code = Generator::new()
+        .gen_state_machines_tokenstream(f, "self.state", "Poll::Ready(0)")
+        .to_string();
+    assert!(code.contains("compile_error"));
+    assert!(code.contains("`co_wait_until` takes exactly one argument"));
+
+    let f: ItemFn = parse_quote! {
+        fn poll_job(&mut self) -> Poll<u32> {
+            co_emit(b"hello");
+            return Poll::Ready(1);
+        }
+    };
+    let code = Generator::new()
+        .gen_state_machines_tokenstream(f, "self.state", "Poll::Ready(0)")
+        .to_string();
+    assert!(code.contains("`co_emit` requires `emit_offset` in `gentian_attr`"));
+
+    let f: ItemFn = parse_quote! {
+        fn poll_job(&mut self) -> Poll<u32> {
+            if self.done {
+                break;
+            }
+            return Poll::Ready(1);
+        }
+    };
+    let code = Generator::new()
+        .gen_state_machines_tokenstream(f, "self.state", "Poll::Ready(0)")
+        .to_string();
+    assert!(code.contains("`break` and `continue` must be inside a loop"));
+}
+
+#[test]
+fn test_progress_places_per_statement() {
+    use crate::generate_state_machines::Generator;
+    use crate::stmt::CoContext;
+    use syn::parse_quote;
+    use syn::ItemFn;
+    let gen = |ticks: syn::Expr| {
+        let f: ItemFn = parse_quote! {
+            fn tick(&mut self) -> Option<u32> {
+                co_wait_ticks(1);
+                co_wait_ticks(2);
+                return Some(0);
+            }
+        };
+        let mut generator = Generator::new();
+        generator.set_co_context(CoContext {
+            ticks: Some(ticks),
+            ..Default::default()
+        });
+        generator
+            .gen_state_machines_tokenstream(f, "self.state", "None")
+            .to_string()
+    };
+    let code = gen(parse_quote! {self.ticks_left});
+    assert!(code.contains("`ticks` is already used by another statement"));
+    let code = gen(parse_quote! {(self.first_left)});
+    assert!(code.contains("`ticks` is already used by another statement"));
+    let code = gen(parse_quote! {(self.first_left,)});
+    assert!(code.contains("`ticks` has no place left for this statement"));
+    let code = gen(parse_quote! {(self.first_left, self.second_left)});
+    assert!(!code.contains("compile_error"));
+    assert!(code.contains("self . first_left = 1"));
+    assert!(code.contains("self . second_left = 2"));
+}
+
+#[test]
+fn test_checkpoint_fns() {
+    use crate::generate_state_machines::Generator;
+    use crate::stmt::CoContext;
+    use syn::parse_quote;
+    use syn::ItemFn;
+    let f: ItemFn = parse_quote! {
+        fn poll_job(&mut self) -> Poll<u32> {
+            co_yield(Poll::Pending);
+            return Poll::Ready(1);
+        }
+    };
+    let gen = |storage: syn::Expr| {
+        let mut generator = Generator::new();
+        generator.set_co_context(CoContext {
+            checkpoint: true,
+            storage: Some(storage),
+            ..Default::default()
+        });
+        generator
+            .gen_state_machines_tokenstream(f.clone(), "self.state", "Poll::Ready(0)")
+            .to_string()
+    };
+    if !cfg!(feature = "serde") {
+        let code = gen(parse_quote! {(self.host, self.disks)});
+        assert!(code.contains("`checkpoint` requires the `serde` feature of gentian"));
+        return;
+    }
+    let code = gen(parse_quote! {(self.host, self.disks)});
+    assert!(code.contains("struct PollJobCheckpoint < State , P0 , P1 >"));
+    assert!(code.contains("state : State , host : P0 , disks : P1 ,"));
+    for storage in [
+        parse_quote! {(self.host, self.conn.host)},
+        parse_quote! {(self.state)},
+        parse_quote! {(self.disks[0])},
+    ] {
+        let code = gen(storage);
+        assert!(code.contains("`checkpoint` names each `storage` place after its field"));
+    }
 }
@@ -0,0 +1,129 @@
+//! Generators built with gentian in a `#![no_std]` crate that doesn't import `Poll`.
+#![no_std]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use core::pin::Pin;
+use core::task::Context;
+use gentian::gentian;
+
+pub struct Source {
+    pub polls: [core::task::Poll<Option<u32>>; 4],
+    pub pos: usize,
+}
+
+impl Source {
+    pub fn poll_next(&mut self) -> core::task::Poll<Option<u32>> {
+        if self.pos == self.polls.len() {
+            return core::task::Poll::Ready(None);
+        }
+        self.pos += 1;
+        self.polls[self.pos - 1]
+    }
+
+    pub fn poll_value(&mut self) -> core::task::Poll<u32> {
+        match self.poll_next() {
+            core::task::Poll::Ready(v) => core::task::Poll::Ready(v.unwrap_or(0)),
+            core::task::Poll::Pending => core::task::Poll::Pending,
+        }
+    }
+
+    pub fn poll_checked(&mut self) -> core::task::Poll<Result<u32, ()>> {
+        match self.poll_next() {
+            core::task::Poll::Ready(v) => core::task::Poll::Ready(v.ok_or(())),
+            core::task::Poll::Pending => core::task::Poll::Pending,
+        }
+    }
+}
+
+pub struct Machine {
+    pub state: u32,
+    pub a: Source,
+    pub b: Source,
+    pub joined: (Option<u32>, Option<u32>),
+    pub sum: u32,
+}
+
+impl Machine {
+    #[gentian]
+    #[gentian_attr(state=self.state,ret_val=core::task::Poll::Ready(Ok(0)),join_slots=self.joined)]
+    pub fn poll_sum(&mut self) -> core::task::Poll<Result<u32, ()>> {
+        co_await(self.a.poll_next());
+        self.sum = co_await(self.a.poll_value());
+        self.sum += co_try_await(self.a.poll_checked());
+        let (x, y) = co_join(self.a.poll_value(), self.b.poll_value());
+        self.sum += x + y;
+        co_select! {
+            v = self.b.poll_value() => {
+                self.sum += v;
+            }
+        }
+        while let Some(v) = co_await_next(self.b.poll_next()) {
+            self.sum += v;
+        }
+        return core::task::Poll::Ready(Ok(self.sum));
+    }
+}
+
+pub struct Waiter {
+    pub state: u32,
+    pub fut: Option<Pin<Box<core::future::Ready<u32>>>>,
+}
+
+impl Waiter {
+    #[gentian]
+    #[gentian_attr(state=self.state,ret_val=core::task::Poll::Ready(0),future_slot=self.fut,cx=cx)]
+    pub fn poll_wait(&mut self, cx: &mut Context<'_>) -> core::task::Poll<u32> {
+        let v = core::future::ready(7).await;
+        return core::task::Poll::Ready(v);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Machine, Source, Waiter};
+    use core::task::Poll;
+
+    #[test]
+    fn test_no_std_generator() {
+        let mut m = Machine {
+            state: 0,
+            a: Source {
+                polls: [
+                    Poll::Pending,
+                    Poll::Ready(Some(1)),
+                    Poll::Ready(Some(2)),
+                    Poll::Ready(Some(3)),
+                ],
+                pos: 0,
+            },
+            b: Source {
+                polls: [
+                    Poll::Ready(Some(10)),
+                    Poll::Pending,
+                    Poll::Ready(Some(20)),
+                    Poll::Ready(Some(30)),
+                ],
+                pos: 0,
+            },
+            joined: (None, None),
+            sum: 0,
+        };
+        assert_eq!(m.poll_sum(), Poll::Pending);
+        assert_eq!(m.poll_sum(), Poll::Pending);
+        assert_eq!(m.poll_sum(), Poll::Ready(Ok(2 + 3 + 0 + 10 + 20 + 30)));
+        assert_eq!(m.poll_sum(), Poll::Ready(Ok(0)));
+    }
+
+    #[test]
+    fn test_no_std_future() {
+        let mut cx = core::task::Context::from_waker(core::task::Waker::noop());
+        let mut w = Waiter {
+            state: 0,
+            fut: None,
+        };
+        assert_eq!(w.poll_wait(&mut cx), Poll::Ready(7));
+        assert!(w.fut.is_none());
+    }
+}
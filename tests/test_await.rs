@@ -0,0 +1,318 @@
+use gentian::gentian;
+use std::task::Poll;
+
+struct Countdown<T> {
+    left: u32,
+    val: T,
+}
+
+impl<T: Clone> Countdown<T> {
+    fn new(left: u32, val: T) -> Self {
+        Countdown { left, val }
+    }
+
+    fn poll(&mut self) -> Poll<T> {
+        if self.left == 0 {
+            return Poll::Ready(self.val.clone());
+        }
+        self.left -= 1;
+        Poll::Pending
+    }
+}
+
+struct Summer {
+    state: u32,
+    a: Countdown<usize>,
+    pair: Countdown<(usize, usize)>,
+    b: Countdown<usize>,
+    total: usize,
+    last: usize,
+}
+
+impl Summer {
+    #[gentian]
+    #[gentian_attr(state=self.state,ret_val=Poll::Ready(0))]
+    fn poll_sum(&mut self) -> Poll<usize> {
+        let n = co_await(self.a.poll());
+        self.total = n;
+        let (x, y) = co_await(self.pair.poll());
+        self.total += x * y;
+        self.last = co_await(self.b.poll());
+        return Poll::Ready(self.total + self.last);
+    }
+}
+
+#[test]
+fn test_co_await_binds_ready_value() {
+    let mut s = Summer {
+        state: 0,
+        a: Countdown::new(2, 1),
+        pair: Countdown::new(1, (3, 4)),
+        b: Countdown::new(3, 100),
+        total: 0,
+        last: 0,
+    };
+    let mut pending = 0;
+    let res = loop {
+        match s.poll_sum() {
+            Poll::Ready(v) => break v,
+            Poll::Pending => pending += 1,
+        }
+    };
+    assert_eq!(pending, 6);
+    assert_eq!(res, 113);
+    assert_eq!(s.last, 100);
+    assert_eq!(s.poll_sum(), Poll::Ready(0));
+}
+
+struct Transfer {
+    state: u32,
+    header: Countdown<Result<usize, String>>,
+    body: Countdown<Result<usize, String>>,
+    total: usize,
+}
+
+impl Transfer {
+    #[gentian]
+    #[gentian_attr(state=self.state,ret_val=Poll::Ready(Ok(0)))]
+    fn poll_transfer(&mut self) -> Poll<Result<usize, String>> {
+        let n = co_try_await(self.header.poll());
+        self.total = n;
+        self.total += co_try_await(self.body.poll());
+        co_try_await(self.body.poll());
+        return Poll::Ready(Ok(self.total));
+    }
+}
+
+#[test]
+fn test_co_try_await() {
+    let mut t = Transfer {
+        state: 0,
+        header: Countdown::new(1, Ok(4)),
+        body: Countdown::new(2, Ok(10)),
+        total: 0,
+    };
+    assert_eq!(t.poll_transfer(), Poll::Pending);
+    assert_eq!(t.poll_transfer(), Poll::Pending);
+    assert_eq!(t.poll_transfer(), Poll::Pending);
+    assert_eq!(t.poll_transfer(), Poll::Ready(Ok(14)));
+
+    let mut t = Transfer {
+        state: 0,
+        header: Countdown::new(0, Ok(4)),
+        body: Countdown::new(1, Err(String::from("reset"))),
+        total: 0,
+    };
+    assert_eq!(t.poll_transfer(), Poll::Pending);
+    assert_eq!(t.poll_transfer(), Poll::Ready(Err(String::from("reset"))));
+    assert_eq!(t.total, 4);
+    assert_eq!(t.poll_transfer(), Poll::Ready(Ok(0)));
+}
+
+struct Rx {
+    polls: Vec<Poll<Option<u32>>>,
+}
+
+impl Rx {
+    fn poll_next(&mut self) -> Poll<Option<u32>> {
+        if self.polls.is_empty() {
+            return Poll::Ready(None);
+        }
+        self.polls.remove(0)
+    }
+}
+
+struct Collector {
+    state: u32,
+    rx: Rx,
+    items: Vec<u32>,
+    non_zero: usize,
+}
+
+impl Collector {
+    #[gentian]
+    #[gentian_attr(state=self.state,ret_val=Poll::Ready(0))]
+    fn poll_collect(&mut self) -> Poll<usize> {
+        while let Some(item) = co_await_next(self.rx.poll_next()) {
+            self.items.push(item * 10);
+            if item == 0 {
+                continue;
+            }
+            self.non_zero += 1;
+        }
+        return Poll::Ready(self.non_zero);
+    }
+}
+
+#[test]
+fn test_co_await_next() {
+    let mut c = Collector {
+        state: 0,
+        rx: Rx {
+            polls: vec![
+                Poll::Pending,
+                Poll::Ready(Some(1)),
+                Poll::Ready(Some(0)),
+                Poll::Pending,
+                Poll::Pending,
+                Poll::Ready(Some(2)),
+            ],
+        },
+        items: Vec::new(),
+        non_zero: 0,
+    };
+    assert_eq!(c.poll_collect(), Poll::Pending);
+    assert_eq!(c.poll_collect(), Poll::Pending);
+    assert_eq!(c.poll_collect(), Poll::Pending);
+    assert_eq!(c.poll_collect(), Poll::Ready(2));
+    assert_eq!(c.items, vec![10, 0, 20]);
+    assert_eq!(c.poll_collect(), Poll::Ready(0));
+}
+
+struct Src {
+    polls: Vec<Poll<u32>>,
+    polled: usize,
+}
+
+impl Src {
+    fn new(polls: Vec<Poll<u32>>) -> Self {
+        Src { polls, polled: 0 }
+    }
+
+    fn poll(&mut self) -> Poll<u32> {
+        self.polled += 1;
+        if self.polls.is_empty() {
+            return Poll::Pending;
+        }
+        self.polls.remove(0)
+    }
+}
+
+struct Race {
+    state: u32,
+    a: Src,
+    b: Src,
+    log: Vec<u32>,
+}
+
+impl Race {
+    #[gentian]
+    #[gentian_attr(state=self.state,ret_val=Poll::Ready(0))]
+    fn poll_race(&mut self) -> Poll<u32> {
+        loop {
+            co_select! {
+                a = self.a.poll() => {
+                    self.log.push(a);
+                }
+                b = self.b.poll() => {
+                    self.log.push(b * 100);
+                    co_yield(Poll::Ready(b));
+                    self.log.push(0);
+                }
+            }
+            if self.log.len() >= 3 {
+                break;
+            }
+        }
+        return Poll::Ready(self.log.len() as u32);
+    }
+}
+
+#[test]
+fn test_co_select() {
+    let mut r = Race {
+        state: 0,
+        a: Src::new(vec![Poll::Pending, Poll::Pending, Poll::Ready(1)]),
+        b: Src::new(vec![Poll::Pending, Poll::Ready(2)]),
+        log: Vec::new(),
+    };
+    assert_eq!(r.poll_race(), Poll::Pending);
+    assert_eq!((r.a.polled, r.b.polled), (1, 1));
+    assert_eq!(r.poll_race(), Poll::Ready(2));
+    assert_eq!((r.a.polled, r.b.polled), (2, 2));
+    assert_eq!(r.poll_race(), Poll::Ready(3));
+    assert_eq!(r.log, vec![200, 0, 1]);
+    assert_eq!((r.a.polled, r.b.polled), (3, 2));
+    assert_eq!(r.poll_race(), Poll::Ready(0));
+}
+
+struct Flush {
+    state: u32,
+    a: Src,
+    b: Src,
+    joined: (Option<u32>, Option<u32>),
+    joined_again: (Option<u32>, Option<u32>),
+    sum: u32,
+}
+
+impl Flush {
+    #[gentian]
+    #[gentian_attr(state=self.state,ret_val=Poll::Ready(0),join_slots=(self.joined,self.joined_again))]
+    fn poll_flush_both(&mut self) -> Poll<u32> {
+        let (x, y) = co_join(self.a.poll(), self.b.poll());
+        self.sum = x + y;
+        let (p, _) = co_join(self.a.poll(), self.b.poll());
+        self.sum += p;
+        return Poll::Ready(self.sum);
+    }
+}
+
+#[test]
+fn test_co_join() {
+    let mut f = Flush {
+        state: 0,
+        a: Src::new(vec![Poll::Ready(1), Poll::Ready(10)]),
+        b: Src::new(vec![
+            Poll::Pending,
+            Poll::Pending,
+            Poll::Ready(2),
+            Poll::Ready(20),
+        ]),
+        joined: (None, None),
+        joined_again: (None, None),
+        sum: 0,
+    };
+    assert_eq!(f.poll_flush_both(), Poll::Pending);
+    assert_eq!(f.poll_flush_both(), Poll::Pending);
+    assert_eq!((f.a.polled, f.b.polled), (1, 2));
+    assert_eq!(f.poll_flush_both(), Poll::Ready(13));
+    assert_eq!((f.a.polled, f.b.polled), (2, 4));
+    assert_eq!((f.joined, f.joined_again), ((None, None), (None, None)));
+}
+
+struct Gate {
+    state: u32,
+    open: bool,
+    passed: u32,
+}
+
+impl Gate {
+    #[gentian]
+    #[gentian_attr(state=self.state,ret_val=Poll::Ready(0))]
+    fn poll_pass(&mut self) -> Poll<u32> {
+        while self.passed < 2 {
+            co_wait_until(self.open);
+            self.open = false;
+            self.passed += 1;
+        }
+        co_wait_until(self.passed == 2);
+        return Poll::Ready(self.passed);
+    }
+}
+
+#[test]
+fn test_co_wait_until() {
+    let mut g = Gate {
+        state: 0,
+        open: false,
+        passed: 0,
+    };
+    assert_eq!(g.poll_pass(), Poll::Pending);
+    assert_eq!(g.poll_pass(), Poll::Pending);
+    g.open = true;
+    assert_eq!(g.poll_pass(), Poll::Pending);
+    assert_eq!(g.passed, 1);
+    g.open = true;
+    assert_eq!(g.poll_pass(), Poll::Ready(2));
+    assert_eq!(g.poll_pass(), Poll::Ready(0));
+}
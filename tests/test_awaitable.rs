@@ -0,0 +1,75 @@
+use gentian::gentian;
+use gentian_core::Awaitable;
+use std::io;
+
+#[derive(Debug, PartialEq)]
+enum Step {
+    Again,
+    Done(u32),
+}
+
+enum TryAgain<T> {
+    Later,
+    Now(T),
+}
+
+impl<T> Awaitable for TryAgain<T> {
+    type Output = T;
+
+    fn into_ready(self) -> Option<T> {
+        match self {
+            TryAgain::Later => None,
+            TryAgain::Now(v) => Some(v),
+        }
+    }
+}
+
+struct Device {
+    state: u32,
+    busy: u32,
+    reads: Vec<io::Result<u32>>,
+    total: u32,
+}
+
+impl Device {
+    fn try_read(&mut self) -> TryAgain<u32> {
+        if self.busy > 0 {
+            self.busy -= 1;
+            return TryAgain::Later;
+        }
+        TryAgain::Now(7)
+    }
+
+    fn read(&mut self) -> io::Result<u32> {
+        self.reads.remove(0)
+    }
+
+    #[gentian]
+    #[gentian_attr(state=self.state,ret_val=Step::Done(0),awaitable,pending=Step::Again)]
+    fn step(&mut self) -> Step {
+        co_await(self.try_read());
+        self.busy = 1;
+        self.total = co_await(self.try_read());
+        let r = co_await(self.read());
+        self.total += r.unwrap_or(100);
+        return Step::Done(self.total);
+    }
+}
+
+#[test]
+fn test_custom_awaitable() {
+    let would_block = || Err(io::Error::from(io::ErrorKind::WouldBlock));
+    let mut d = Device {
+        state: 0,
+        busy: 2,
+        reads: vec![would_block(), Err(io::Error::from(io::ErrorKind::Other))],
+        total: 0,
+    };
+    assert_eq!(d.step(), Step::Again);
+    assert_eq!(d.step(), Step::Again);
+    assert_eq!(d.step(), Step::Again);
+    assert_eq!(d.step(), Step::Again);
+    assert_eq!(d.total, 7);
+    assert_eq!(d.step(), Step::Done(107));
+    assert_eq!(d.step(), Step::Done(0));
+}
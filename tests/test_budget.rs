@@ -0,0 +1,96 @@
+use gentian::gentian;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+
+#[derive(Default)]
+struct CountingWaker {
+    wakes: AtomicUsize,
+}
+
+impl Wake for CountingWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.wakes.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+struct Summer {
+    state: u32,
+    i: u32,
+    sum: u32,
+}
+
+impl Summer {
+    #[gentian]
+    #[gentian_attr(state=self.state,ret_val=Poll::Ready(0),cx=cx,budget=3)]
+    fn poll_sum(&mut self, cx: &mut Context<'_>) -> Poll<u32> {
+        while self.i < 10 {
+            self.sum += self.i;
+            self.i += 1;
+        }
+        return Poll::Ready(self.sum);
+    }
+
+    #[gentian]
+    #[gentian_attr(state=self.state,ret_val=Poll::Ready(0),cx=cx,budget=3)]
+    fn poll_odd_sum(&mut self, cx: &mut Context<'_>) -> Poll<u32> {
+        loop {
+            self.i += 1;
+            if self.i % 2 == 0 {
+                continue;
+            }
+            if self.i > 10 {
+                break;
+            }
+            self.sum += self.i;
+        }
+        return Poll::Ready(self.sum);
+    }
+}
+
+#[test]
+fn test_budget_while() {
+    let w = Arc::new(CountingWaker::default());
+    let waker = Waker::from(w.clone());
+    let mut cx = Context::from_waker(&waker);
+    let mut s = Summer {
+        state: 0,
+        i: 0,
+        sum: 0,
+    };
+    assert_eq!(s.poll_sum(&mut cx), Poll::Pending);
+    assert_eq!(s.i, 4);
+    assert_eq!(w.wakes.load(Ordering::SeqCst), 1);
+    assert_eq!(s.poll_sum(&mut cx), Poll::Pending);
+    assert_eq!(s.i, 8);
+    assert_eq!(s.poll_sum(&mut cx), Poll::Ready(45));
+    assert_eq!(w.wakes.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn test_budget_continue() {
+    let w = Arc::new(CountingWaker::default());
+    let waker = Waker::from(w.clone());
+    let mut cx = Context::from_waker(&waker);
+    let mut s = Summer {
+        state: 0,
+        i: 0,
+        sum: 0,
+    };
+    let mut pending = 0;
+    let sum = loop {
+        match s.poll_odd_sum(&mut cx) {
+            Poll::Ready(sum) => break sum,
+            Poll::Pending => pending += 1,
+        }
+    };
+    assert_eq!(sum, 1 + 3 + 5 + 7 + 9);
+    // `continue` and the end of the body are two back edges, each with a budget of its own.
+    assert_eq!(pending, 1);
+    assert_eq!(s.i, 11);
+    assert_eq!(w.wakes.load(Ordering::SeqCst), 1);
+}
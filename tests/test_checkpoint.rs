@@ -0,0 +1,67 @@
+use gentian::gentian;
+use std::task::Poll;
+
+#[derive(Default)]
+struct Provision {
+    state: u32,
+    host: Option<String>,
+    disks: u32,
+    log: Vec<String>,
+}
+
+impl Provision {
+    #[gentian]
+    #[gentian_attr(state=self.state,ret_val=Poll::Ready(0),storage=(self.host, self.disks),checkpoint)]
+    fn poll_provision(&mut self) -> Poll<u32> {
+        self.host = Some(String::from("db-1"));
+        co_yield(Poll::Pending);
+        while self.disks < 3 {
+            self.disks += 1;
+            self.log.push(format!("disk {}", self.disks));
+            co_yield(Poll::Pending);
+        }
+        self.log.push(format!("boot {}", self.host.as_ref().unwrap()));
+        return Poll::Ready(self.disks);
+    }
+}
+
+#[test]
+fn test_checkpoint_round_trip() {
+    let mut p = Provision::default();
+    assert!(p.poll_provision().is_pending());
+    assert!(p.poll_provision().is_pending());
+    let mut buf = Vec::new();
+    p.poll_provision_save(&mut serde_json::Serializer::new(&mut buf))
+        .unwrap();
+    assert_eq!(
+        String::from_utf8_lossy(&buf),
+        format!(r#"{{"state":{},"host":"db-1","disks":1}}"#, p.state)
+    );
+
+    // a restarted process resumes from the checkpoint.
+    let mut q = Provision::default();
+    q.poll_provision_load(&mut serde_json::Deserializer::from_slice(&buf))
+        .unwrap();
+    assert_eq!(q.state, p.state);
+    assert_eq!(q.host.as_deref(), Some("db-1"));
+    assert_eq!(q.disks, 1);
+    let ret = loop {
+        if let Poll::Ready(ret) = q.poll_provision() {
+            break ret;
+        }
+    };
+    assert_eq!(ret, 3);
+    assert_eq!(q.log, ["disk 2", "disk 3", "boot db-1"]);
+}
+
+#[test]
+fn test_checkpoint_rejects_unknown_state() {
+    let mut p = Provision::default();
+    let err = p
+        .poll_provision_load(&mut serde_json::Deserializer::from_str(
+            r#"{"state":999,"host":null,"disks":0}"#,
+        ))
+        .unwrap_err();
+    assert!(err.to_string().contains("`poll_provision` has no state 999"));
+    assert_eq!(p.state, 0);
+}
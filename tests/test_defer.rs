@@ -0,0 +1,145 @@
+use gentian::gentian;
+use std::io;
+use std::num::ParseIntError;
+use std::task::Poll;
+
+struct Job {
+    state: u32,
+    log: Vec<&'static str>,
+    next: Option<io::Result<u32>>,
+    n: u32,
+}
+
+impl Job {
+    fn new(next: io::Result<u32>) -> Self {
+        Job {
+            state: 0,
+            log: Vec::new(),
+            next: Some(next),
+            n: 0,
+        }
+    }
+
+    #[gentian]
+    #[gentian_attr(state=self.state,ret_val=Poll::Ready(Ok(u32::MAX)),reset)]
+    fn poll_job(&mut self) -> Poll<io::Result<u32>> {
+        self.log.push("open");
+        co_defer! {
+            self.log.push("close");
+        }
+        co_yield(Poll::Pending);
+        self.n = co_try_await(Poll::Ready(self.next.take().unwrap()));
+        co_defer! {
+            self.log.push("flush");
+        }
+        co_yield(Poll::Pending);
+        if self.n == 0 {
+            return Poll::Ready(Ok(self.log.len() as u32));
+        }
+        self.log.push("done");
+    }
+}
+
+fn run(job: &mut Job) -> io::Result<u32> {
+    loop {
+        if let Poll::Ready(res) = job.poll_job() {
+            return res;
+        }
+    }
+}
+
+#[test]
+fn test_co_defer_on_finish() {
+    let mut job = Job::new(Ok(3));
+    assert_eq!(run(&mut job).unwrap(), u32::MAX);
+    assert_eq!(job.log, ["open", "done", "flush", "close"]);
+    assert_eq!(job.poll_job().map(Result::unwrap), Poll::Ready(u32::MAX));
+    assert_eq!(job.log.len(), 4);
+}
+
+#[test]
+fn test_co_defer_on_return() {
+    let mut job = Job::new(Ok(0));
+    // the value is evaluated before the deferred blocks run.
+    assert_eq!(run(&mut job).unwrap(), 1);
+    assert_eq!(job.log, ["open", "flush", "close"]);
+}
+
+#[test]
+fn test_co_defer_on_error() {
+    let mut job = Job::new(Err(io::ErrorKind::BrokenPipe.into()));
+    assert_eq!(run(&mut job).unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+    assert_eq!(job.log, ["open", "close"]);
+}
+
+#[test]
+fn test_co_defer_on_reset() {
+    let mut job = Job::new(Ok(3));
+    job.poll_job_reset();
+    assert!(job.log.is_empty());
+    assert!(job.poll_job().is_pending());
+    job.poll_job_reset();
+    assert_eq!(job.log, ["open", "close"]);
+    assert_eq!(job.state, 0);
+    job.next = Some(Ok(3));
+    assert!(job.poll_job().is_pending());
+    assert!(job.poll_job().is_pending());
+    job.poll_job_reset();
+    assert_eq!(job.log, ["open", "close", "open", "flush", "close"]);
+}
+
+struct Parse {
+    state: u32,
+    log: Vec<&'static str>,
+    input: &'static str,
+    limit: &'static str,
+    n: u32,
+}
+
+impl Parse {
+    fn new(input: &'static str, limit: &'static str) -> Self {
+        Parse {
+            state: 0,
+            log: Vec::new(),
+            input,
+            limit,
+            n: 0,
+        }
+    }
+
+    #[gentian]
+    #[gentian_attr(state=self.state,ret_val=Poll::Ready(Ok(0)))]
+    fn poll_parse(&mut self) -> Poll<Result<u32, ParseIntError>> {
+        co_defer! {
+            self.log.push("close");
+        }
+        co_yield(Poll::Pending);
+        self.n = self.input.parse()?;
+        if self.limit.parse::<u32>()? < self.n {
+            self.log.push("over");
+        }
+        self.log.push("parsed");
+        return Poll::Ready(Ok(self.n));
+    }
+}
+
+#[test]
+fn test_co_defer_on_try() {
+    let mut p = Parse::new("7", "5");
+    assert!(p.poll_parse().is_pending());
+    assert_eq!(p.poll_parse(), Poll::Ready(Ok(7)));
+    assert_eq!(p.log, ["over", "parsed", "close"]);
+
+    let mut p = Parse::new("x", "5");
+    assert!(p.poll_parse().is_pending());
+    assert!(matches!(p.poll_parse(), Poll::Ready(Err(_))));
+    assert_eq!(p.log, ["close"]);
+    assert_eq!(p.poll_parse(), Poll::Ready(Ok(0)));
+    assert_eq!(p.log, ["close"]);
+
+    let mut p = Parse::new("7", "y");
+    assert!(p.poll_parse().is_pending());
+    assert!(matches!(p.poll_parse(), Poll::Ready(Err(_))));
+    assert_eq!(p.log, ["close"]);
+    assert_eq!(p.poll_parse(), Poll::Ready(Ok(0)));
+}
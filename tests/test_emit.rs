@@ -0,0 +1,51 @@
+use gentian::gentian;
+use std::io;
+use std::task::Poll;
+
+struct Decrypted {
+    state: u32,
+    emit_pos: usize,
+    chunks: Vec<Vec<u8>>,
+    cur: Vec<u8>,
+}
+
+impl Decrypted {
+    fn new(chunks: Vec<Vec<u8>>) -> Self {
+        Decrypted {
+            state: 0,
+            emit_pos: 0,
+            chunks,
+            cur: Vec::new(),
+        }
+    }
+
+    #[gentian]
+    #[gentian_attr(state=self.state,ret_val=Poll::Ready(Ok(0)),emit_buf=dst,emit_offset=self.emit_pos)]
+    fn poll_read(&mut self, dst: &mut [u8]) -> Poll<io::Result<usize>> {
+        while !self.chunks.is_empty() {
+            self.cur = self.chunks.remove(0);
+            co_emit(self.cur);
+        }
+        return Poll::Ready(Ok(0));
+    }
+}
+
+#[test]
+fn test_co_emit_partial_buffer() {
+    let mut r = Decrypted::new(vec![b"hello".to_vec(), Vec::new(), b"world!".to_vec()]);
+    let mut out = Vec::new();
+    let mut buf = [0u8; 4];
+    loop {
+        match r.poll_read(&mut buf) {
+            Poll::Ready(Ok(0)) => break,
+            Poll::Ready(Ok(n)) => {
+                assert!(n <= buf.len());
+                out.extend_from_slice(&buf[..n]);
+            }
+            _ => unreachable!(),
+        }
+    }
+    assert_eq!(out, b"helloworld!");
+    assert_eq!(r.emit_pos, 0);
+    assert!(matches!(r.poll_read(&mut buf), Poll::Ready(Ok(0))));
+}
@@ -0,0 +1,68 @@
+use gentian::gentian;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+
+struct NoopWaker;
+
+impl Wake for NoopWaker {
+    fn wake(self: Arc<Self>) {}
+}
+
+struct Delay {
+    left: u32,
+    val: u32,
+}
+
+impl Future for Delay {
+    type Output = u32;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<u32> {
+        if self.left == 0 {
+            return Poll::Ready(self.val);
+        }
+        self.left -= 1;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+struct Timer {
+    state: u32,
+    sleep: Option<Pin<Box<Delay>>>,
+    again: Option<Pin<Box<dyn Future<Output = u32>>>>,
+    total: u32,
+}
+
+impl Timer {
+    #[gentian]
+    #[gentian_attr(state=self.state,ret_val=Poll::Ready(0),future_slot=(self.sleep,self.again))]
+    fn poll_total(&mut self, cx: &mut Context<'_>) -> Poll<u32> {
+        let a = co_await_future(Delay { left: 2, val: 5 });
+        self.total = a;
+        self.total += Delay { left: 1, val: 7 }.await;
+        return Poll::Ready(self.total);
+    }
+}
+
+#[test]
+fn test_await_future() {
+    let waker = Waker::from(Arc::new(NoopWaker));
+    let mut cx = Context::from_waker(&waker);
+    let mut t = Timer {
+        state: 0,
+        sleep: None,
+        again: None,
+        total: 0,
+    };
+    assert_eq!(t.poll_total(&mut cx), Poll::Pending);
+    assert!(t.sleep.is_some());
+    assert_eq!(t.poll_total(&mut cx), Poll::Pending);
+    assert_eq!(t.poll_total(&mut cx), Poll::Pending);
+    assert_eq!(t.total, 5);
+    assert!(t.sleep.is_none() && t.again.is_some());
+    assert_eq!(t.poll_total(&mut cx), Poll::Ready(12));
+    assert!(t.again.is_none());
+    assert_eq!(t.poll_total(&mut cx), Poll::Ready(0));
+}
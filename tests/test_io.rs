@@ -0,0 +1,196 @@
+use gentian::gentian;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+
+struct Socket {
+    reads: Vec<io::Result<usize>>,
+    calls: usize,
+}
+
+impl Socket {
+    fn read(&mut self) -> io::Result<usize> {
+        self.calls += 1;
+        self.reads.remove(0)
+    }
+}
+
+fn would_block() -> io::Result<usize> {
+    Err(io::Error::from(io::ErrorKind::WouldBlock))
+}
+
+struct Conn {
+    state: u32,
+    socket: Socket,
+    total: usize,
+}
+
+impl Conn {
+    #[gentian]
+    #[gentian_attr(state=self.state,ret_val=Poll::Ready(Ok(0)))]
+    fn poll_read_two(&mut self) -> Poll<io::Result<usize>> {
+        let n = co_await_io(self.socket.read());
+        self.total = n;
+        self.total += co_await_io(self.socket.read());
+        return Poll::Ready(Ok(self.total));
+    }
+}
+
+#[test]
+fn test_co_await_io() {
+    let mut c = Conn {
+        state: 0,
+        socket: Socket {
+            reads: vec![would_block(), Ok(3), would_block(), would_block(), Ok(4)],
+            calls: 0,
+        },
+        total: 0,
+    };
+    assert!(c.poll_read_two().is_pending());
+    assert!(c.poll_read_two().is_pending());
+    assert!(c.poll_read_two().is_pending());
+    assert_eq!(c.poll_read_two().map(|r| r.unwrap()), Poll::Ready(7));
+    assert_eq!(c.socket.calls, 5);
+
+    let mut c = Conn {
+        state: 0,
+        socket: Socket {
+            reads: vec![Ok(3), Err(io::Error::from(io::ErrorKind::ConnectionReset))],
+            calls: 0,
+        },
+        total: 0,
+    };
+    match c.poll_read_two() {
+        Poll::Ready(Err(e)) => assert_eq!(e.kind(), io::ErrorKind::ConnectionReset),
+        _ => panic!("expected the reset to be propagated"),
+    }
+    assert_eq!(c.poll_read_two().map(|r| r.unwrap()), Poll::Ready(0));
+}
+
+trait AsyncRead {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8])
+        -> Poll<io::Result<usize>>;
+}
+
+trait AsyncWrite {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8])
+        -> Poll<io::Result<usize>>;
+}
+
+struct NoopWaker;
+
+impl Wake for NoopWaker {
+    fn wake(self: Arc<Self>) {}
+}
+
+/// Hands out at most two bytes per poll and is pending every other poll.
+struct Trickle {
+    data: Vec<u8>,
+    written: Vec<u8>,
+    ready: bool,
+}
+
+impl AsyncRead for Trickle {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        self.ready = !self.ready;
+        if !self.ready {
+            return Poll::Pending;
+        }
+        let n = buf.len().min(2).min(self.data.len());
+        buf[..n].copy_from_slice(&self.data[..n]);
+        self.data.drain(..n);
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl AsyncWrite for Trickle {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.ready = !self.ready;
+        if !self.ready {
+            return Poll::Pending;
+        }
+        let n = buf.len().min(2);
+        self.written.extend_from_slice(&buf[..n]);
+        Poll::Ready(Ok(n))
+    }
+}
+
+struct Echo {
+    state: u32,
+    read_pos: usize,
+    write_pos: usize,
+    stream: Trickle,
+    header: [u8; 5],
+}
+
+impl Echo {
+    #[gentian]
+    #[gentian_attr(state=self.state,ret_val=Poll::Ready(Ok(0)),io_offset=(self.read_pos,self.write_pos))]
+    fn poll_echo(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        co_read_exact(self.stream, cx, &mut self.header);
+        co_write_all(self.stream, cx, &self.header);
+        return Poll::Ready(Ok(self.header.len()));
+    }
+}
+
+#[test]
+fn test_co_read_exact_and_co_write_all() {
+    let waker = Waker::from(Arc::new(NoopWaker));
+    let mut cx = Context::from_waker(&waker);
+    let mut e = Echo {
+        state: 0,
+        read_pos: 0,
+        write_pos: 0,
+        stream: Trickle {
+            data: b"hello world".to_vec(),
+            written: Vec::new(),
+            ready: false,
+        },
+        header: [0; 5],
+    };
+    assert!(e.poll_echo(&mut cx).is_pending());
+    assert_eq!(e.read_pos, 2);
+    let mut pending = 1;
+    let n = loop {
+        match e.poll_echo(&mut cx) {
+            Poll::Ready(res) => break res.unwrap(),
+            Poll::Pending => pending += 1,
+        }
+    };
+    assert_eq!(n, 5);
+    assert_eq!(pending, 5);
+    assert_eq!(&e.header, b"hello");
+    assert_eq!(e.stream.written, b"hello");
+    assert_eq!((e.read_pos, e.write_pos), (0, 0));
+
+    let mut e = Echo {
+        state: 0,
+        read_pos: 0,
+        write_pos: 0,
+        stream: Trickle {
+            data: b"hi".to_vec(),
+            written: Vec::new(),
+            ready: false,
+        },
+        header: [0; 5],
+    };
+    let res = loop {
+        if let Poll::Ready(res) = e.poll_echo(&mut cx) {
+            break res;
+        }
+    };
+    match res {
+        Err(err) => assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof),
+        _ => panic!("expected an early eof"),
+    }
+    assert_eq!((e.read_pos, e.write_pos), (0, 0));
+}
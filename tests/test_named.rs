@@ -0,0 +1,68 @@
+use gentian::gentian;
+use std::task::Poll;
+
+struct Reader {
+    state: u32,
+    header: Poll<u8>,
+    body: Poll<u8>,
+    len: u8,
+    body_len: u8,
+}
+
+impl Reader {
+    #[gentian]
+    #[gentian_attr(state=self.state,ret_val=Poll::Ready(0),state_type=u32)]
+    fn poll_read(&mut self) -> Poll<u8> {
+        #[gentian_state(name = "await_header")]
+        self.len = co_await(self.header);
+        #[gentian_state(name = "await_body")]
+        self.body_len = co_await(self.body);
+        return Poll::Ready(self.len + self.body_len);
+    }
+}
+
+#[test]
+fn test_named_states() {
+    let mut r = Reader {
+        state: 0,
+        header: Poll::Pending,
+        body: Poll::Pending,
+        len: 0,
+        body_len: 0,
+    };
+    assert_ne!(Reader::POLL_READ_AWAIT_HEADER, Reader::POLL_READ_AWAIT_BODY);
+    assert!(r.poll_read().is_pending());
+    assert_eq!(r.state, Reader::POLL_READ_AWAIT_HEADER);
+    r.header = Poll::Ready(2);
+    assert!(r.poll_read().is_pending());
+    assert_eq!(r.state, Reader::POLL_READ_AWAIT_BODY);
+    r.body = Poll::Ready(3);
+    assert_eq!(r.poll_read(), Poll::Ready(5));
+}
+
+struct Ticker {
+    state: usize,
+    ready: bool,
+}
+
+impl Ticker {
+    #[gentian]
+    #[gentian_attr(state=self.state,ret_val=Poll::Ready(()))]
+    fn poll_tick(&mut self) -> Poll<()> {
+        #[gentian_state(name = "wait_ready")]
+        co_wait_until(self.ready);
+        return Poll::Ready(());
+    }
+}
+
+#[test]
+fn test_named_states_default_type() {
+    let mut t = Ticker {
+        state: 0,
+        ready: false,
+    };
+    assert!(t.poll_tick().is_pending());
+    assert_eq!(t.state, Ticker::POLL_TICK_WAIT_READY);
+    t.ready = true;
+    assert_eq!(t.poll_tick(), Poll::Ready(()));
+}
@@ -0,0 +1,60 @@
+use gentian::gentian;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+struct Worker {
+    state: u32,
+    n: u32,
+}
+
+impl Worker {
+    #[gentian]
+    #[gentian_attr(state=self.state,ret_val=None,poison_on_panic,reset)]
+    fn next(&mut self) -> Option<u32> {
+        loop {
+            self.n += 1;
+            if self.n == 2 {
+                panic!("boom");
+            }
+            co_yield(Some(self.n));
+        }
+    }
+
+    #[gentian]
+    #[gentian_attr(state=self.state,ret_val=None,poison_on_panic,poisoned=Some(u32::MAX))]
+    fn next_or_max(&mut self) -> Option<u32> {
+        loop {
+            self.n += 1;
+            if self.n == 2 {
+                panic!("boom");
+            }
+            co_yield(Some(self.n));
+        }
+    }
+}
+
+#[test]
+fn test_poison_on_panic() {
+    let mut w = Worker { state: 0, n: 0 };
+    assert_eq!(w.next(), Some(1));
+    assert!(catch_unwind(AssertUnwindSafe(|| w.next())).is_err());
+    let err = catch_unwind(AssertUnwindSafe(|| w.next())).unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<&str>(),
+        Some(&"`next` resumed after panicking")
+    );
+    assert_eq!(w.n, 2);
+
+    // a reset clears the poison.
+    w.next_reset();
+    assert_eq!(w.next(), Some(3));
+    assert_eq!(w.next(), Some(4));
+}
+
+#[test]
+fn test_poisoned_value() {
+    let mut w = Worker { state: 0, n: 0 };
+    assert_eq!(w.next_or_max(), Some(1));
+    assert!(catch_unwind(AssertUnwindSafe(|| w.next_or_max())).is_err());
+    assert_eq!(w.next_or_max(), Some(u32::MAX));
+    assert_eq!(w.next_or_max(), Some(u32::MAX));
+}
@@ -0,0 +1,94 @@
+use gentian::gentian;
+
+struct Accumulator {
+    state: u32,
+    total: i32,
+    last: i32,
+}
+
+impl Accumulator {
+    #[gentian]
+    #[gentian_attr(state=self.state,ret_val=None,resume_arg=input)]
+    fn feed(&mut self, input: i32) -> Option<i32> {
+        self.total = input;
+        loop {
+            let x = co_yield(Some(self.total));
+            self.total += x;
+            if x < 0 {
+                break;
+            }
+            self.last = co_yield(Some(self.total));
+            self.total *= self.last;
+        }
+        return None;
+    }
+}
+
+#[test]
+fn test_resume_argument() {
+    let mut acc = Accumulator {
+        state: 0,
+        total: 0,
+        last: 0,
+    };
+    assert_eq!(acc.feed(1), Some(1));
+    assert_eq!(acc.feed(2), Some(3));
+    assert_eq!(acc.feed(10), Some(30));
+    assert_eq!(acc.last, 10);
+    assert_eq!(acc.feed(-1), None);
+    assert_eq!(acc.total, 29);
+    assert_eq!(acc.feed(5), None);
+}
+
+struct Lines {
+    state: u32,
+    line: String,
+    out: Vec<String>,
+}
+
+impl Lines {
+    #[gentian]
+    #[gentian_attr(state=self.state,ret_val=None,resume_arg=input)]
+    fn push(&mut self, input: String) -> Option<usize> {
+        loop {
+            self.out.push(input);
+            co_yield(Some(self.out.len()));
+        }
+    }
+
+    #[gentian]
+    #[gentian_attr(state=self.state,ret_val=None,resume_arg=input)]
+    fn collect(&mut self, input: String) -> Option<usize> {
+        loop {
+            self.line = co_yield(Some(self.out.len()));
+            if self.line.is_empty() {
+                break;
+            }
+            self.out.push(std::mem::take(&mut self.line));
+        }
+        return None;
+    }
+}
+
+#[test]
+fn test_resume_argument_not_copy() {
+    let mut lines = Lines {
+        state: 0,
+        line: String::new(),
+        out: Vec::new(),
+    };
+    assert_eq!(lines.push(String::from("a")), Some(1));
+    assert_eq!(lines.push(String::from("b")), Some(2));
+    assert_eq!(lines.out, ["a", "b"]);
+
+    let mut lines = Lines {
+        state: 0,
+        line: String::new(),
+        out: Vec::new(),
+    };
+    assert_eq!(lines.collect(String::from("ignored")), Some(0));
+    assert_eq!(lines.collect(String::from("a")), Some(1));
+    assert_eq!(lines.collect(String::from("b")), Some(2));
+    assert_eq!(lines.collect(String::new()), None);
+    assert_eq!(lines.out, ["a", "b"]);
+}
@@ -0,0 +1,33 @@
+use gentian::gentian;
+
+struct Handshake {
+    state: u32,
+    step: u32,
+}
+
+impl Handshake {
+    #[gentian]
+    #[gentian_attr(state=self.state,ret_val=None,stable_states)]
+    fn next(&mut self) -> Option<u32> {
+        self.step = 1;
+        co_yield(Some(self.step));
+        #[state("handshake_done")]
+        co_yield(Some(2));
+        while self.step < 4 {
+            self.step += 1;
+            co_yield(Some(self.step));
+        }
+    }
+}
+
+#[test]
+fn test_stable_states() {
+    let mut h = Handshake { state: 0, step: 0 };
+    let mut out = Vec::new();
+    while let Some(v) = h.next() {
+        out.push(v);
+    }
+    assert_eq!(out, [1, 2, 2, 3, 4]);
+    // ids are hashes, so they are not small sequential numbers.
+    assert!(h.state > 100);
+}
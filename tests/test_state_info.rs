@@ -0,0 +1,80 @@
+use gentian::gentian;
+use std::task::Poll;
+
+struct Conn {
+    state: u32,
+    header: Poll<u8>,
+    len: u8,
+}
+
+impl Conn {
+    #[gentian]
+    #[gentian_attr(state=self.state,ret_val=Poll::Ready(0),state_info)]
+    fn poll_conn(&mut self) -> Poll<u8> {
+        co_yield(Poll::Pending);
+        #[gentian_state(name = "await_header")]
+        self.len = co_await(self.header);
+        return Poll::Ready(self.len);
+    }
+
+    #[gentian]
+    #[gentian_attr(state=self.state,ret_val=Poll::Ready(0),state_info)]
+    fn poll_header(&mut self) -> Poll<u8> {
+        #[gentian_state(name = "await_header")]
+        let len = co_await(self.header);
+        return Poll::Ready(len);
+    }
+}
+
+fn source_line(line: u32) -> &'static str {
+    include_str!("test_state_info.rs")
+        .lines()
+        .nth(line as usize - 1)
+        .unwrap()
+        .trim()
+}
+
+#[test]
+fn test_state_info() {
+    let mut c = Conn {
+        state: 0,
+        header: Poll::Pending,
+        len: 0,
+    };
+    let info = c.poll_conn_state_info();
+    assert_eq!(info.state, 0);
+    assert!(source_line(info.line).starts_with("fn poll_conn"));
+
+    assert!(c.poll_conn().is_pending());
+    let info = c.poll_conn_state_info();
+    assert!(info.file.ends_with("test_state_info.rs"));
+    assert_eq!(source_line(info.line), "co_yield(Poll::Pending);");
+    assert_eq!(info.name, None);
+
+    assert!(c.poll_conn().is_pending());
+    let info = c.poll_conn_state_info();
+    assert_eq!(info.state, c.state as u64);
+    assert_eq!(info.name, Some("await_header"));
+    assert_eq!(source_line(info.line), "self.len = co_await(self.header);");
+    assert!(info.to_string().starts_with("state "));
+
+    c.header = Poll::Ready(7);
+    assert_eq!(c.poll_conn(), Poll::Ready(7));
+    c.state = 1000;
+    assert_eq!(c.poll_conn_state_info().line, 0);
+}
+
+#[test]
+fn test_state_info_let() {
+    let mut c = Conn {
+        state: 0,
+        header: Poll::Pending,
+        len: 0,
+    };
+    assert!(c.poll_header().is_pending());
+    let info = c.poll_header_state_info();
+    assert_eq!(info.name, Some("await_header"));
+    assert_eq!(source_line(info.line), "let len = co_await(self.header);");
+    c.header = Poll::Ready(3);
+    assert_eq!(c.poll_header(), Poll::Ready(3));
+}
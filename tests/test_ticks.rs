@@ -0,0 +1,66 @@
+use gentian::gentian;
+
+struct Blinker {
+    state: u32,
+    ticks_left: u32,
+    slow_ticks_left: u32,
+    done_ticks_left: u32,
+    fast: bool,
+    flashes: u32,
+}
+
+impl Blinker {
+    fn new(fast: bool) -> Self {
+        Blinker {
+            state: 0,
+            ticks_left: 0,
+            slow_ticks_left: 0,
+            done_ticks_left: 0,
+            fast,
+            flashes: 0,
+        }
+    }
+
+    #[gentian]
+    #[gentian_attr(state=self.state,ret_val=None,pending=None,ticks=(self.ticks_left,self.slow_ticks_left,self.done_ticks_left))]
+    fn tick(&mut self) -> Option<u32> {
+        loop {
+            if self.fast {
+                co_wait_ticks(1);
+            } else {
+                co_wait_ticks(3);
+            }
+            self.flashes += 1;
+            if self.flashes == 3 {
+                break;
+            }
+        }
+        co_wait_ticks(0);
+        return Some(self.flashes);
+    }
+}
+
+fn ticks_until_done(b: &mut Blinker) -> u32 {
+    let mut n = 1;
+    while b.tick().is_none() {
+        n += 1;
+    }
+    n
+}
+
+#[test]
+fn test_co_wait_ticks() {
+    let mut b = Blinker::new(false);
+    assert_eq!(ticks_until_done(&mut b), 10);
+    assert_eq!(b.slow_ticks_left, 0);
+    assert_eq!(b.tick(), None);
+
+    let mut b = Blinker::new(true);
+    assert_eq!(ticks_until_done(&mut b), 4);
+
+    let mut b = Blinker::new(false);
+    assert_eq!(b.tick(), None);
+    assert_eq!(b.slow_ticks_left, 2);
+    b.fast = true;
+    assert_eq!(ticks_until_done(&mut b), 5);
+}
@@ -0,0 +1,114 @@
+use gentian::gentian;
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use std::task::Poll;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Metadata, Subscriber};
+
+#[derive(Default)]
+struct Transitions {
+    spans: Mutex<Vec<String>>,
+    events: Mutex<Vec<(u64, u64)>>,
+}
+
+#[derive(Default)]
+struct FromTo(u64, u64);
+
+impl Visit for FromTo {
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        match field.name() {
+            "from" => self.0 = value,
+            "to" => self.1 = value,
+            _ => {}
+        }
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.record_u64(field, value as u64);
+    }
+
+    fn record_debug(&mut self, _field: &Field, _value: &dyn Debug) {}
+}
+
+struct Recorder(Arc<Transitions>);
+
+impl Subscriber for Recorder {
+    fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, span: &Attributes<'_>) -> Id {
+        let mut spans = self.0.spans.lock().unwrap();
+        spans.push(span.metadata().name().to_string());
+        Id::from_u64(spans.len() as u64)
+    }
+
+    fn record(&self, _span: &Id, _values: &Record<'_>) {}
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut from_to = FromTo::default();
+        event.record(&mut from_to);
+        self.0.events.lock().unwrap().push((from_to.0, from_to.1));
+    }
+
+    fn enter(&self, _span: &Id) {}
+
+    fn exit(&self, _span: &Id) {}
+}
+
+struct Counter {
+    state: u32,
+    i: u32,
+}
+
+impl Counter {
+    #[gentian]
+    #[gentian_attr(state=self.state,ret_val=Poll::Ready(0),tracing)]
+    fn poll_count(&mut self) -> Poll<u32> {
+        while self.i < 2 {
+            self.i += 1;
+            co_yield(Poll::Pending);
+        }
+        return Poll::Ready(self.i);
+    }
+
+    #[gentian]
+    #[gentian_attr(state=self.state,ret_val=Poll::Ready(0))]
+    fn poll_count_quietly(&mut self) -> Poll<u32> {
+        while self.i < 2 {
+            self.i += 1;
+            co_yield(Poll::Pending);
+        }
+        return Poll::Ready(self.i);
+    }
+}
+
+#[test]
+fn test_tracing_transitions() {
+    let transitions = Arc::new(Transitions::default());
+    let mut c = Counter { state: 0, i: 0 };
+    tracing::subscriber::with_default(Recorder(transitions.clone()), || {
+        while c.poll_count().is_pending() {}
+    });
+    let spans = transitions.spans.lock().unwrap();
+    assert_eq!(spans.len(), 3);
+    assert!(spans.iter().all(|name| name == "poll_count"));
+    let events = transitions.events.lock().unwrap();
+    assert_eq!(events.first().unwrap().0, 0);
+    assert!(events.windows(2).all(|w| w[0].1 == w[1].0));
+    assert_eq!(events.last().unwrap().1 as u32, c.state);
+}
+
+#[test]
+fn test_tracing_is_opt_in() {
+    let transitions = Arc::new(Transitions::default());
+    let mut c = Counter { state: 0, i: 0 };
+    tracing::subscriber::with_default(Recorder(transitions.clone()), || {
+        while c.poll_count_quietly().is_pending() {}
+    });
+    assert!(transitions.spans.lock().unwrap().is_empty());
+    assert!(transitions.events.lock().unwrap().is_empty());
+}
@@ -0,0 +1,71 @@
+use gentian::gentian;
+use std::cell::RefCell;
+use std::task::Poll;
+
+thread_local! {
+    static TRANSITIONS: RefCell<Vec<(u32, u32)>> = RefCell::new(Vec::new());
+}
+
+fn record_transition(from: u32, to: u32) {
+    TRANSITIONS.with(|t| t.borrow_mut().push((from, to)));
+}
+
+struct Counter {
+    state: u32,
+    i: u32,
+    transitions: Vec<(u32, u32)>,
+}
+
+impl Counter {
+    fn record(&mut self, from: u32, to: u32) {
+        self.transitions.push((from, to));
+    }
+
+    #[gentian]
+    #[gentian_attr(state=self.state,ret_val=Poll::Ready(0),on_transition=Self::record)]
+    fn poll_count(&mut self) -> Poll<u32> {
+        while self.i < 2 {
+            self.i += 1;
+            co_yield(Poll::Pending);
+        }
+        return Poll::Ready(self.i);
+    }
+
+    #[gentian]
+    #[gentian_attr(state=self.state,ret_val=Poll::Ready(0),on_transition=record_transition)]
+    fn poll_free(&mut self) -> Poll<u32> {
+        self.i += 1;
+        co_yield(Poll::Pending);
+        return Poll::Ready(self.i);
+    }
+}
+
+#[test]
+fn test_on_transition_method() {
+    let mut c = Counter {
+        state: 0,
+        i: 0,
+        transitions: Vec::new(),
+    };
+    while c.poll_count().is_pending() {}
+    assert_eq!(c.transitions.first().unwrap().0, 0);
+    assert!(c.transitions.windows(2).all(|w| w[0].1 == w[1].0));
+    assert_eq!(c.transitions.last().unwrap().1, c.state);
+    assert_eq!(c.poll_count(), Poll::Ready(0));
+}
+
+#[test]
+fn test_on_transition_function() {
+    let mut c = Counter {
+        state: 0,
+        i: 0,
+        transitions: Vec::new(),
+    };
+    assert!(c.poll_free().is_pending());
+    let parked = c.state;
+    assert_eq!(c.poll_free(), Poll::Ready(1));
+    let transitions = TRANSITIONS.with(|t| t.borrow().clone());
+    assert_eq!(transitions.first(), Some(&(0, parked)));
+    assert_eq!(transitions.last().unwrap().1, c.state);
+    assert!(c.transitions.is_empty());
+}
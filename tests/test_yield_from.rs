@@ -0,0 +1,59 @@
+use gentian::gentian;
+use std::ops::Range;
+
+struct Evens {
+    state: u32,
+    n: u32,
+    limit: u32,
+}
+
+impl Iterator for Evens {
+    type Item = u32;
+
+    #[gentian]
+    #[gentian_attr(ret_val=None)]
+    fn next(&mut self) -> Option<u32> {
+        while self.n < self.limit {
+            co_yield(Some(self.n));
+            self.n += 2;
+        }
+        return None;
+    }
+}
+
+struct Outer {
+    state: u32,
+    range: Range<u32>,
+    evens: Evens,
+}
+
+impl Iterator for Outer {
+    type Item = u32;
+
+    #[gentian]
+    #[gentian_attr(ret_val=None)]
+    fn next(&mut self) -> Option<u32> {
+        co_yield(Some(100));
+        co_yield_from(self.range);
+        co_yield_from(self.evens);
+        self.range = 7..9;
+        co_yield_from(self.range);
+        co_yield(Some(200));
+        return None;
+    }
+}
+
+#[test]
+fn test_co_yield_from() {
+    let outer = Outer {
+        state: 0,
+        range: 0..3,
+        evens: Evens {
+            state: 0,
+            n: 0,
+            limit: 6,
+        },
+    };
+    let values: Vec<u32> = outer.collect();
+    assert_eq!(values, vec![100, 0, 1, 2, 0, 2, 4, 7, 8, 200]);
+}